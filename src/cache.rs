@@ -0,0 +1,331 @@
+//! In-memory data cache. Every file is split into `block_size` blocks, which
+//! are evicted one by one, least recently used first.
+
+use crate::range::Range;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+struct CachedFile {
+    length: u64,
+    // One bit per block, set when the block is present.
+    bitmap: Vec<u64>,
+    blocks: HashMap<u64, Vec<u8>>,
+}
+
+impl CachedFile {
+    fn new(length: u64, block_size: u64) -> CachedFile {
+        let count = length.div_ceil(block_size);
+        CachedFile {
+            length,
+            bitmap: vec![0; count.div_ceil(64) as usize],
+            blocks: HashMap::new(),
+        }
+    }
+
+    fn has(&self, block: u64) -> bool {
+        self.bitmap
+            .get((block / 64) as usize)
+            .is_some_and(|word| word & (1 << (block % 64)) != 0)
+    }
+
+    fn set(&mut self, block: u64) {
+        self.bitmap[(block / 64) as usize] |= 1 << (block % 64);
+    }
+
+    fn unset(&mut self, block: u64) {
+        self.bitmap[(block / 64) as usize] &= !(1 << (block % 64));
+    }
+
+    fn bytes(&self) -> u64 {
+        self.blocks.values().map(|block| block.len() as u64).sum()
+    }
+}
+
+pub struct BlockCache {
+    block_size: u64,
+    max_files: usize,
+    max_bytes: u64,
+    bytes: u64,
+    files: HashMap<u64, CachedFile>,
+    // Files, least recently used first.
+    lru: VecDeque<u64>,
+    // Last use -> file and block, least recently used first.
+    blocks_lru: BTreeMap<u64, (u64, u64)>,
+    // File and block -> last use.
+    used: HashMap<(u64, u64), u64>,
+    tick: u64,
+}
+
+impl BlockCache {
+    pub fn new(block_size: u64, max_files: usize, max_bytes: u64) -> BlockCache {
+        BlockCache {
+            block_size,
+            max_files,
+            max_bytes,
+            bytes: 0,
+            files: HashMap::new(),
+            lru: VecDeque::new(),
+            blocks_lru: BTreeMap::new(),
+            used: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Byte range of `block` in a file of `length` bytes.
+    fn block_range(&self, block: u64, length: u64) -> Range {
+        Range {
+            start: block * self.block_size,
+            end: ((block + 1) * self.block_size).min(length),
+        }
+    }
+
+    /// Block-aligned byte ranges of `range` that aren't cached yet.
+    /// Adjacent missing blocks are merged so each run costs a single request.
+    pub fn missing(&self, ino: u64, length: u64, range: Range) -> Vec<Range> {
+        let file = self.files.get(&ino).filter(|file| file.length == length);
+        let mut runs: Vec<Range> = Vec::new();
+        if range.end <= range.start {
+            return runs;
+        }
+        let first = range.start / self.block_size;
+        let last = (range.end - 1) / self.block_size;
+        for block in first..=last {
+            if file.is_some_and(|file| file.has(block)) {
+                continue;
+            }
+            let block_range = self.block_range(block, length);
+            match runs.last_mut() {
+                Some(run) if run.end == block_range.start => run.end = block_range.end,
+                _ => runs.push(block_range),
+            }
+        }
+        runs
+    }
+
+    /// Store `data` fetched from `start` (block aligned). Only whole blocks are kept.
+    pub fn insert(&mut self, ino: u64, length: u64, start: u64, data: &[u8]) {
+        if self
+            .files
+            .get(&ino)
+            .is_some_and(|file| file.length != length)
+        {
+            self.forget(ino);
+        }
+        let block_size = self.block_size;
+        let file = self
+            .files
+            .entry(ino)
+            .or_insert_with(|| CachedFile::new(length, block_size));
+        let mut added = 0;
+        for (i, piece) in data.chunks(block_size as usize).enumerate() {
+            let block = start / block_size + i as u64;
+            let expected =
+                ((block + 1) * block_size).min(length) - (block * block_size).min(length);
+            if piece.len() as u64 != expected || expected == 0 || file.has(block) {
+                continue;
+            }
+            file.set(block);
+            file.blocks.insert(block, piece.to_vec());
+            added += piece.len() as u64;
+        }
+        let first = start / block_size;
+        let count = (data.len() as u64).div_ceil(block_size);
+        // Pieces which weren't kept have nothing to count use of.
+        let stored: Vec<u64> = (first..first + count)
+            .filter(|&block| file.has(block))
+            .collect();
+        self.bytes += added;
+        self.touch(ino);
+        for block in stored {
+            self.touch_block(ino, block);
+        }
+        self.evict(ino, first..first + count);
+    }
+
+    /// Cached bytes of `range`. Stops at the first missing block, so the result
+    /// may be shorter than requested.
+    pub fn read(&mut self, ino: u64, range: Range) -> Vec<u8> {
+        let mut chunk = Vec::with_capacity(range.len() as usize);
+        let mut read = Vec::new();
+        if let Some(file) = self.files.get(&ino) {
+            let mut pos = range.start;
+            while pos < range.end {
+                let block = pos / self.block_size;
+                let data = match file.blocks.get(&block) {
+                    Some(data) => data,
+                    None => break,
+                };
+                let from = (pos - block * self.block_size) as usize;
+                let to = ((range.end - block * self.block_size) as usize).min(data.len());
+                if from >= to {
+                    break;
+                }
+                chunk.extend_from_slice(&data[from..to]);
+                read.push(block);
+                pos += (to - from) as u64;
+            }
+            self.touch(ino);
+        }
+        for block in read {
+            self.touch_block(ino, block);
+        }
+        chunk
+    }
+
+    /// How many bytes of the file are cached.
+    pub fn cached_bytes(&self, ino: u64) -> u64 {
+        self.files.get(&ino).map_or(0, |file| file.bytes())
+    }
+
+    /// Bytes held by the whole cache.
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn forget(&mut self, ino: u64) {
+        if let Some(file) = self.files.remove(&ino) {
+            self.bytes -= file.bytes();
+        }
+        let blocks_lru = &mut self.blocks_lru;
+        self.used.retain(|&(file, _), tick| {
+            if file == ino {
+                blocks_lru.remove(tick);
+            }
+            file != ino
+        });
+        self.lru.retain(|&x| x != ino);
+    }
+
+    fn touch(&mut self, ino: u64) {
+        if self.lru.back() != Some(&ino) {
+            self.lru.retain(|&x| x != ino);
+            self.lru.push_back(ino);
+        }
+    }
+
+    fn touch_block(&mut self, ino: u64, block: u64) {
+        self.tick += 1;
+        if let Some(old) = self.used.insert((ino, block), self.tick) {
+            self.blocks_lru.remove(&old);
+        }
+        self.blocks_lru.insert(self.tick, (ino, block));
+    }
+
+    /// Drop least recently used files until there are few enough of them,
+    /// then least recently used blocks until the size fits. Only `blocks`
+    /// of `keep`, just stored, are spared: a long file read from start to end
+    /// loses its early blocks as it goes.
+    fn evict(&mut self, keep: u64, blocks: std::ops::Range<u64>) {
+        while self.files.len() > self.max_files {
+            let old = match self.lru.iter().find(|&&x| x != keep) {
+                Some(&old) => old,
+                None => break,
+            };
+            debug!("{} - Cache dropped", old);
+            self.forget(old);
+        }
+        let mut spared = Vec::new();
+        while self.bytes > self.max_bytes {
+            let (tick, (ino, block)) = match self.blocks_lru.iter().next() {
+                Some((&tick, &entry)) => (tick, entry),
+                None => break,
+            };
+            self.blocks_lru.remove(&tick);
+            if ino == keep && blocks.contains(&block) {
+                spared.push((tick, (ino, block)));
+                continue;
+            }
+            self.used.remove(&(ino, block));
+            let file = match self.files.get_mut(&ino) {
+                Some(file) => file,
+                None => continue,
+            };
+            if let Some(data) = file.blocks.remove(&block) {
+                self.bytes -= data.len() as u64;
+            }
+            file.unset(block);
+            if file.blocks.is_empty() {
+                debug!("{} - Cache dropped", ino);
+                self.files.remove(&ino);
+                self.lru.retain(|&x| x != ino);
+            }
+        }
+        self.blocks_lru.extend(spared);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn r(start: u64, end: u64) -> Range {
+        Range { start, end }
+    }
+
+    #[test]
+    fn partial_overlap() {
+        let mut cache = BlockCache::new(10, 10, 1 << 20);
+        let data: Vec<u8> = (0..45).collect();
+        assert_eq!(cache.missing(2, 45, r(5, 25)), vec![r(0, 30)]);
+        cache.insert(2, 45, 10, &data[10..30]);
+        assert_eq!(cache.missing(2, 45, r(5, 25)), vec![r(0, 10)]);
+        assert_eq!(cache.missing(2, 45, r(5, 45)), vec![r(0, 10), r(30, 45)]);
+        assert_eq!(cache.read(2, r(5, 25)), Vec::<u8>::new());
+        assert_eq!(cache.read(2, r(12, 25)), data[12..25].to_vec());
+        // Last block is shorter than block size.
+        cache.insert(2, 45, 40, &data[40..45]);
+        assert_eq!(cache.read(2, r(41, 45)), data[41..45].to_vec());
+        assert_eq!(cache.cached_bytes(2), 25);
+        // Short piece which isn't a whole block is not kept.
+        cache.insert(2, 45, 0, &data[0..7]);
+        assert_eq!(cache.cached_bytes(2), 25);
+    }
+
+    #[test]
+    fn eviction() {
+        let mut cache = BlockCache::new(10, 2, 25);
+        cache.insert(2, 10, 0, &[0; 10]);
+        cache.insert(3, 10, 0, &[0; 10]);
+        cache.read(2, r(0, 10));
+        cache.insert(4, 10, 0, &[0; 10]);
+        assert_eq!(cache.cached_bytes(3), 0);
+        assert_eq!(cache.cached_bytes(2), 10);
+        cache.insert(4, 30, 0, &[0; 30]);
+        assert_eq!(cache.total_bytes(), 30);
+        assert_eq!(cache.cached_bytes(2), 0);
+    }
+
+    #[test]
+    fn long_file() {
+        // Read from start to end, a file bigger than the cache keeps its
+        // latest blocks only.
+        let mut cache = BlockCache::new(10, 2, 30);
+        cache.insert(2, 100, 90, &[0; 10]);
+        for start in (0..80).step_by(10) {
+            cache.insert(3, 100, start, &[0; 10]);
+        }
+        assert_eq!(cache.total_bytes(), 30);
+        assert_eq!(cache.cached_bytes(2), 0);
+        assert_eq!(cache.missing(3, 100, r(0, 80)), vec![r(0, 50)]);
+        // Blocks in use stay while older ones of the same file go.
+        cache.read(3, r(50, 60));
+        cache.insert(3, 100, 80, &[0; 10]);
+        assert_eq!(cache.missing(3, 100, r(50, 90)), vec![r(60, 70)]);
+    }
+
+    #[test]
+    fn short_pieces() {
+        // A short piece isn't stored, nor may it linger in the LRU order.
+        let mut cache = BlockCache::new(10, 1, 15);
+        cache.insert(2, 45, 0, &[0; 7]);
+        cache.insert(2, 45, 10, &[0; 10]);
+        cache.insert(3, 45, 0, &[0; 10]);
+        cache.insert(3, 45, 10, &[0; 10]);
+        assert_eq!(cache.cached_bytes(2), 0);
+        assert_eq!(cache.total_bytes(), 10);
+        assert_eq!(cache.missing(3, 45, r(0, 20)), vec![r(0, 10)]);
+    }
+}
@@ -0,0 +1,620 @@
+//! CUE sheets of single file rips, and cutting such files into tracks: WAV
+//! by plain byte ranges, FLAC at frame boundaries under a new STREAMINFO.
+//! FLAC frames are served as they are, so their headers keep the frame or
+//! sample numbers of the whole file; decoders play such streams, but strict
+//! validators complain about the first frame not being number zero.
+
+use crate::{
+    get_basename,
+    tags::{self, be32, id3v2_len, Block},
+    Track,
+};
+use std::convert::TryInto;
+
+/// CD frames per second, the unit of CUE times.
+const CD_FRAMES: u64 = 75;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// CD frames from the beginning of the file.
+    pub start: u64,
+}
+
+/// Tracks of one FILE of a CUE sheet.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sheet {
+    pub file: String,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub tracks: Vec<CueTrack>,
+}
+
+/// Text of a CUE sheet, which is often in a legacy 8-bit encoding.
+pub fn decode(data: &[u8]) -> String {
+    let data = data.strip_prefix(b"\xef\xbb\xbf").unwrap_or(data);
+    match std::str::from_utf8(data) {
+        Ok(text) => text.to_string(),
+        Err(_) => data.iter().map(|&byte| byte as char).collect(),
+    }
+}
+
+/// Argument of a command: quoted string or the first word.
+fn argument(rest: &str) -> String {
+    let rest = rest.trim();
+    match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next().unwrap_or_default().to_string(),
+        None => rest
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+/// `mm:ss:ff` in CD frames.
+fn time(value: &str) -> Option<u64> {
+    let parts: Vec<u64> = value
+        .split(':')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<_>>()?;
+    match parts[..] {
+        [minutes, seconds, frames] => Some((minutes * 60 + seconds) * CD_FRAMES + frames),
+        _ => None,
+    }
+}
+
+/// Parse a CUE sheet into its files and their tracks.
+pub fn parse(text: &str) -> Result<Vec<Sheet>, String> {
+    let mut sheets: Vec<Sheet> = Vec::new();
+    let mut album = Sheet::default();
+    // INDEX 00 (pregap) counts only if there is no INDEX 01.
+    let mut pregap = None;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let track = sheets.last_mut().and_then(|sheet| sheet.tracks.last_mut());
+        match (command.to_ascii_uppercase().as_str(), track) {
+            ("FILE", _) => sheets.push(Sheet {
+                file: argument(rest),
+                tracks: Vec::new(),
+                ..album.clone()
+            }),
+            ("TRACK", _) => {
+                let sheet = sheets
+                    .last_mut()
+                    .ok_or_else(|| format!("TRACK before FILE at line {}", n + 1))?;
+                let number = argument(rest)
+                    .parse::<u32>()
+                    .map_err(|_| format!("Wrong track number at line {}", n + 1))?;
+                sheet.tracks.push(CueTrack {
+                    number,
+                    start: u64::MAX,
+                    ..Default::default()
+                });
+                pregap = None;
+            }
+            ("INDEX", Some(track)) => {
+                let mut words = rest.split_whitespace();
+                let index = words.next().unwrap_or_default();
+                let start = words
+                    .next()
+                    .and_then(time)
+                    .ok_or_else(|| format!("Wrong INDEX at line {}", n + 1))?;
+                match index {
+                    "01" => track.start = start,
+                    "00" => pregap = Some(start),
+                    _ => (),
+                }
+            }
+            ("TITLE", Some(track)) => track.title = Some(argument(rest)),
+            ("PERFORMER", Some(track)) => track.performer = Some(argument(rest)),
+            ("TITLE", None) => album.title = Some(argument(rest)),
+            ("PERFORMER", None) => album.performer = Some(argument(rest)),
+            ("REM", None) => match rest.split_once(char::is_whitespace) {
+                Some((key, value)) if key.eq_ignore_ascii_case("GENRE") => {
+                    album.genre = Some(argument(value))
+                }
+                Some((key, value)) if key.eq_ignore_ascii_case("DATE") => {
+                    album.year = argument(value).get(..4).and_then(|y| y.parse().ok())
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+        if let Some(track) = sheets.last_mut().and_then(|sheet| sheet.tracks.last_mut()) {
+            if track.start == u64::MAX {
+                if let Some(pregap) = pregap {
+                    track.start = pregap;
+                }
+            }
+        }
+    }
+    for sheet in sheets.iter_mut() {
+        sheet.tracks.retain(|track| track.start != u64::MAX);
+        // Album fields may come after FILE.
+        sheet.title = sheet.title.take().or_else(|| album.title.clone());
+        sheet.performer = sheet.performer.take().or_else(|| album.performer.clone());
+        sheet.genre = sheet.genre.take().or_else(|| album.genre.clone());
+        sheet.year = sheet.year.or(album.year);
+    }
+    sheets.retain(|sheet| !sheet.tracks.is_empty());
+    if sheets.is_empty() {
+        return Err("No tracks".to_string());
+    }
+    Ok(sheets)
+}
+
+/// Extensions of files which can be cut into tracks.
+pub const SPLITTABLE: [&str; 2] = ["flac", "wav"];
+
+/// A track of a CUE sheet served as a file of its own.
+#[derive(Debug, Clone)]
+pub struct Part {
+    /// Inode of the whole file.
+    pub source: u64,
+    /// Metadata for the tags of the part.
+    pub track: Track,
+    /// CD frames.
+    pub start: u64,
+    /// Start of the next track, or the end of file for the last one.
+    pub end: Option<u64>,
+}
+
+pub fn is_cue(track: &Track) -> bool {
+    get_basename(track.path.as_ref())
+        .is_some_and(|name| name.to_ascii_lowercase().ends_with(".cue"))
+}
+
+fn stem(name: &str) -> &str {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => &name[..dot],
+        _ => name,
+    }
+}
+
+/// Lower case extension of a file name.
+pub fn extension(name: &str) -> String {
+    name[stem(name).len()..]
+        .trim_start_matches('.')
+        .to_lowercase()
+}
+
+/// Library index of the file `sheet` refers to, found in the directory of
+/// the CUE sheet `cue`. It is named as in FILE, or has the stem of FILE or
+/// of the sheet itself: rips are often converted after the sheet is made.
+pub fn audio_file(tracks: &[Track], cue: usize, sheet: &Sheet) -> Option<usize> {
+    let dir = |track: &Track| {
+        let path = track.path.as_deref().unwrap_or_default();
+        path[..path.rfind('/').unwrap_or(0)].to_string()
+    };
+    let cue_dir = dir(&tracks[cue]);
+    let cue_name = get_basename(tracks[cue].path.as_ref())?.to_lowercase();
+    let file = sheet.file.rsplit(['/', '\\']).next()?.to_lowercase();
+    let candidates: Vec<(usize, String)> = tracks
+        .iter()
+        .enumerate()
+        .filter(|(i, track)| *i != cue && dir(track) == cue_dir)
+        .filter_map(|(i, track)| Some((i, get_basename(track.path.as_ref())?.to_lowercase())))
+        .filter(|(_, name)| SPLITTABLE.contains(&extension(name).as_str()))
+        .collect();
+    candidates
+        .iter()
+        .find(|(_, name)| *name == file)
+        .or_else(|| {
+            candidates
+                .iter()
+                .find(|(_, name)| stem(name) == stem(&file))
+        })
+        .or_else(|| {
+            candidates
+                .iter()
+                .find(|(_, name)| stem(name) == stem(&cue_name))
+        })
+        .map(|(i, _)| *i)
+}
+
+/// Metadata of each track of `sheet`, on top of that of the whole `file`.
+pub fn tracks(sheet: &Sheet, file: &Track) -> Vec<Track> {
+    let whole = file
+        .duration
+        .map(|duration| (duration * CD_FRAMES as f64) as u64);
+    sheet
+        .tracks
+        .iter()
+        .enumerate()
+        .map(|(i, track)| {
+            let end = sheet.tracks.get(i + 1).map(|next| next.start).or(whole);
+            Track {
+                id: Some(format!(
+                    "{}#{}",
+                    file.id.as_deref().unwrap_or_default(),
+                    track.number
+                )),
+                name: track.title.clone(),
+                artist: track
+                    .performer
+                    .clone()
+                    .or_else(|| sheet.performer.clone())
+                    .or_else(|| file.artist.clone()),
+                album: sheet.title.clone().or_else(|| file.album.clone()),
+                album_artist: sheet
+                    .performer
+                    .clone()
+                    .or_else(|| file.album_artist.clone()),
+                genre: sheet.genre.clone().or_else(|| file.genre.clone()),
+                year: sheet.year.or(file.year),
+                track_number: Some(track.number),
+                duration: end.map(|end| end.saturating_sub(track.start) as f64 / CD_FRAMES as f64),
+                ..file.clone()
+            }
+        })
+        .collect()
+}
+
+/// Sample at CUE time `start`.
+pub fn sample(start: u64, rate: u64) -> u64 {
+    start * rate / CD_FRAMES
+}
+
+/// PCM WAV file layout.
+pub struct Wav<'a> {
+    pub fmt: &'a [u8],
+    pub data_start: u64,
+    pub data_len: u64,
+    pub rate: u64,
+    pub block_align: u64,
+}
+
+/// Layout of a WAV file from its beginning.
+pub fn wav(head: &[u8]) -> Option<Wav<'_>> {
+    if head.get(..4)? != b"RIFF" || head.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let mut fmt = None;
+    let mut pos = 12;
+    while pos + 8 <= head.len() {
+        let kind = &head[pos..pos + 4];
+        let len = u32::from_le_bytes(head[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        if kind == b"data" {
+            let fmt: &[u8] = fmt?;
+            let rate = u32::from_le_bytes(fmt.get(4..8)?.try_into().unwrap()) as u64;
+            let block_align = u16::from_le_bytes(fmt.get(12..14)?.try_into().unwrap()) as u64;
+            if rate == 0 || block_align == 0 {
+                return None;
+            }
+            return Some(Wav {
+                fmt,
+                data_start: pos as u64 + 8,
+                data_len: len as u64,
+                rate,
+                block_align,
+            });
+        }
+        if kind == b"fmt " {
+            fmt = Some(head.get(pos + 8..pos + 8 + len)?);
+        }
+        // Chunks are word aligned.
+        pos += 8 + len + len % 2;
+    }
+    None
+}
+
+/// Header of a WAV file with `data_len` bytes of audio in format `fmt`.
+pub fn wav_header(fmt: &[u8], data_len: u64) -> Vec<u8> {
+    let fmt_len = fmt.len() + fmt.len() % 2;
+    let mut header = b"RIFF".to_vec();
+    header.extend_from_slice(&((4 + 8 + fmt_len as u64 + 8 + data_len) as u32).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+    header.extend_from_slice(fmt);
+    header.resize(header.len() + fmt_len - fmt.len(), 0);
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&(data_len as u32).to_le_bytes());
+    header
+}
+
+/// FLAC stream parameters and metadata, from the beginning of a file.
+pub struct Flac<'a> {
+    pub blocks: Vec<Block<'a>>,
+    pub audio_start: u64,
+    pub rate: u64,
+    pub block_size: u64,
+    pub total: u64,
+    /// Sample and offset from `audio_start` of frames in SEEKTABLE.
+    pub seek_points: Vec<(u64, u64)>,
+}
+
+/// FLAC layout of `head`, which must hold all metadata.
+pub fn flac(head: &[u8]) -> Option<Flac<'_>> {
+    let id3_end = id3v2_len(head).unwrap_or(0);
+    let rest = head.get(id3_end..)?;
+    if !rest.starts_with(b"fLaC") {
+        return None;
+    }
+    let (blocks, end) = tags::flac_blocks(rest);
+    let end = end.ok()?;
+    let streaminfo = blocks.first().filter(|(kind, _)| *kind == 0)?.1;
+    if streaminfo.len() < 34 {
+        return None;
+    }
+    let rate =
+        (streaminfo[10] as u64) << 12 | (streaminfo[11] as u64) << 4 | (streaminfo[12] as u64) >> 4;
+    let total = ((streaminfo[13] & 0x0f) as u64) << 32 | be32(&streaminfo[14..]) as u64;
+    let block_size = u16::from_be_bytes([streaminfo[0], streaminfo[1]]) as u64;
+    let seek_points = blocks
+        .iter()
+        .filter(|(kind, _)| *kind == 3)
+        .flat_map(|(_, table)| table.chunks_exact(18))
+        .map(|point| {
+            let sample = u64::from_be_bytes(point[..8].try_into().unwrap());
+            let offset = u64::from_be_bytes(point[8..16].try_into().unwrap());
+            (sample, offset)
+        })
+        // Placeholder points
+        .filter(|&(sample, _)| sample != u64::MAX)
+        .collect();
+    if rate == 0 {
+        return None;
+    }
+    Some(Flac {
+        blocks,
+        audio_start: (id3_end + end) as u64,
+        rate,
+        block_size,
+        total,
+        seek_points,
+    })
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// First sample and the number of samples of the FLAC frame whose header
+/// starts `data`, if it does.
+fn frame_header(data: &[u8], block_size: u64) -> Option<(u64, u64)> {
+    let header = data.get(..4)?;
+    if header[0] != 0xff
+        || header[1] & 0xfe != 0xf8
+        || header[2] >> 4 == 0
+        || header[2] & 0x0f == 0x0f
+        || header[3] >> 4 > 10
+        || header[3] & 0x01 != 0
+    {
+        return None;
+    }
+    // Frame or sample number, coded like UTF-8.
+    let first = *data.get(4)?;
+    let extra = (first.leading_ones() as usize).saturating_sub(1);
+    if first & 0xc0 == 0x80 || extra > 6 {
+        return None;
+    }
+    let mut number = (first & (0x7f >> extra)) as u64;
+    for i in 0..extra {
+        let byte = *data.get(5 + i)?;
+        if byte & 0xc0 != 0x80 {
+            return None;
+        }
+        number = number << 6 | (byte & 0x3f) as u64;
+    }
+    let mut len = 5 + extra;
+    let samples = match header[2] >> 4 {
+        1 => 192,
+        code @ 2..=5 => 576 << (code - 2),
+        6 => {
+            len += 1;
+            *data.get(len - 1)? as u64 + 1
+        }
+        7 => {
+            len += 2;
+            u16::from_be_bytes([*data.get(len - 2)?, *data.get(len - 1)?]) as u64 + 1
+        }
+        code => 256 << (code - 8),
+    };
+    len += match header[2] & 0x0f {
+        12 => 1,
+        13 | 14 => 2,
+        _ => 0,
+    };
+    if crc8(data.get(..len)?) != *data.get(len)? {
+        return None;
+    }
+    // Variable block size streams number samples, fixed ones frames.
+    let first = if header[1] & 0x01 != 0 {
+        number
+    } else {
+        number * block_size
+    };
+    Some((first, samples))
+}
+
+/// Offsets and first samples of FLAC frames found in `data`. Audio may look
+/// like a frame header, so a frame counts only if the next one starts where
+/// it ends, or, if `data` runs to the end of file, it ends with a right CRC.
+pub fn frames(data: &[u8], block_size: u64, at_end: bool) -> Vec<(usize, u64)> {
+    let candidates: Vec<(usize, u64, u64)> = (0..data.len().saturating_sub(1))
+        .filter(|&i| data[i] == 0xff && data[i + 1] & 0xfe == 0xf8)
+        .filter_map(|i| {
+            let (first, samples) = frame_header(&data[i..], block_size)?;
+            Some((i, first, samples))
+        })
+        .collect();
+    let last_frame = |pos: usize| {
+        data.len() >= pos + 2
+            && crc16(&data[pos..data.len() - 2]).to_be_bytes() == data[data.len() - 2..]
+    };
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|&(n, &(pos, first, samples))| {
+            candidates[n + 1..]
+                .iter()
+                .any(|&(_, next, _)| next == first + samples)
+                || (at_end && last_frame(pos))
+        })
+        .map(|(_, &(pos, first, _))| (pos, first))
+        .collect()
+}
+
+/// STREAMINFO of a part of the stream with `total` samples.
+pub fn streaminfo(original: &[u8], total: u64) -> Vec<u8> {
+    let mut streaminfo = original.to_vec();
+    streaminfo[13] = (streaminfo[13] & 0xf0) | (total >> 32) as u8 & 0x0f;
+    streaminfo[14..18].copy_from_slice(&(total as u32).to_be_bytes());
+    // The MD5 of the whole stream doesn't match a part of it.
+    streaminfo[18..34].fill(0);
+    streaminfo
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sheet() {
+        let text = "REM GENRE Rock\r\nREM DATE 1997\r\nPERFORMER \"Band\"\r\n\
+            TITLE \"Live\"\r\nFILE \"Live.flac\" WAVE\r\n  TRACK 01 AUDIO\r\n    \
+            TITLE \"Intro\"\r\n    INDEX 01 00:00:00\r\n  TRACK 02 AUDIO\r\n    \
+            TITLE \"Song\"\r\n    PERFORMER \"Guest\"\r\n    INDEX 00 03:10:00\r\n    \
+            INDEX 01 03:12:37\r\n  TRACK 03 AUDIO\r\n    INDEX 00 05:00:00\r\n";
+        let sheets = parse(text).unwrap();
+        assert_eq!(sheets.len(), 1);
+        let sheet = &sheets[0];
+        assert_eq!(sheet.file, "Live.flac");
+        assert_eq!(sheet.performer.as_deref(), Some("Band"));
+        assert_eq!(
+            (sheet.year, sheet.genre.as_deref()),
+            (Some(1997), Some("Rock"))
+        );
+        let starts: Vec<u64> = sheet.tracks.iter().map(|track| track.start).collect();
+        assert_eq!(starts, vec![0, 192 * 75 + 37, 300 * 75]);
+        assert_eq!(sheet.tracks[1].performer.as_deref(), Some("Guest"));
+        assert_eq!(sample(75, 44100), 44100);
+        assert!(parse("TITLE \"Nothing\"").is_err());
+        assert!(parse("TRACK 01 AUDIO").is_err());
+    }
+
+    #[test]
+    fn pairing() {
+        let track = |path: &str| Track {
+            id: Some(path.to_string()),
+            path: Some(path.to_string()),
+            album: Some("From library".to_string()),
+            duration: Some(400.0),
+            ..Default::default()
+        };
+        let tracks = vec![
+            track("/a/Live.cue"),
+            track("/a/Live.mp3"),
+            track("/b/Live.flac"),
+            track("/a/Live.flac"),
+            track("/a/Other.wav"),
+        ];
+        let mut sheet = parse(
+            "FILE \"Live.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+            TRACK 02 AUDIO\nTITLE \"Two\"\nINDEX 01 01:40:00",
+        )
+        .unwrap()
+        .remove(0);
+        assert!(is_cue(&tracks[0]) && !is_cue(&tracks[3]));
+        assert_eq!(audio_file(&tracks, 0, &sheet), Some(3));
+        sheet.file = "Other.WAV".to_string();
+        assert_eq!(audio_file(&tracks, 0, &sheet), Some(4));
+        sheet.file = "Missing.ape".to_string();
+        assert_eq!(audio_file(&tracks, 0, &sheet), Some(3));
+
+        let parts = super::tracks(&sheet, &tracks[3]);
+        assert_eq!(parts[0].id.as_deref(), Some("/a/Live.flac#1"));
+        assert_eq!(parts[1].name.as_deref(), Some("Two"));
+        assert_eq!(parts[1].album.as_deref(), Some("From library"));
+        assert_eq!(
+            (parts[0].duration, parts[1].duration),
+            (Some(100.0), Some(300.0))
+        );
+    }
+
+    #[test]
+    fn wav_layout() {
+        let mut fmt = vec![1, 0, 2, 0];
+        fmt.extend_from_slice(&44100u32.to_le_bytes());
+        fmt.extend_from_slice(&(44100u32 * 4).to_le_bytes());
+        fmt.extend_from_slice(&[4, 0, 16, 0]);
+        let header = wav_header(&fmt, 400);
+        assert_eq!(header.len(), 44);
+        let wav = wav(&header).unwrap();
+        assert_eq!(
+            (wav.data_start, wav.data_len, wav.rate, wav.block_align),
+            (44, 400, 44100, 4)
+        );
+        assert!(super::wav(b"RIFF\0\0\0\0WAVE").is_none());
+    }
+
+    #[test]
+    fn flac_frames() {
+        // Fixed block size 4096, 44.1 kHz, stereo, 16 bit.
+        let frame = |number: u8| {
+            let mut frame = vec![0xff, 0xf8, 0xc9, 0x18, number];
+            frame.push(crc8(&frame));
+            frame.extend_from_slice(&[0x12, 0x34]);
+            frame
+        };
+        let mut data = vec![0xff, 0xf8, 0x00];
+        data.extend(frame(2));
+        // Looks like frame 9 but isn't followed by frame 10.
+        data.extend(frame(9));
+        data.extend(frame(3));
+        let mut last = frame(4);
+        let crc = crc16(&last);
+        last.extend_from_slice(&crc.to_be_bytes());
+        data.extend(last);
+        assert_eq!(frames(&data, 4096, false), vec![(3, 8192), (19, 12288)]);
+        assert_eq!(
+            frames(&data, 4096, true),
+            vec![(3, 8192), (19, 12288), (27, 16384)]
+        );
+
+        let mut info = vec![0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0];
+        info.extend_from_slice(&[0x0a, 0xc4, 0x40, 0x00]);
+        info.extend_from_slice(&441000u32.to_be_bytes());
+        info.extend_from_slice(&[0xaa; 16]);
+        let mut head = b"fLaC\x80\x00\x00\x22".to_vec();
+        head.extend_from_slice(&info);
+        let flac = flac(&head).unwrap();
+        assert_eq!(
+            (flac.audio_start, flac.rate, flac.block_size, flac.total),
+            (42, 44100, 4096, 441000)
+        );
+        let mut short = b"fLaC\x80\x00\x00\x12".to_vec();
+        short.extend_from_slice(&info[..18]);
+        assert!(super::flac(&short).is_none());
+        let cut = streaminfo(&info, 1000);
+        assert_eq!(be32(&cut[14..]), 1000);
+        assert_eq!(&cut[18..], &[0; 16]);
+        assert_eq!(&cut[..14], &info[..14]);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::{limit, range::Range, HTTP_AUTH, METRICS};
+use reqwest::{
+    blocking::{Client, Response},
+    header::{CONTENT_RANGE, RANGE},
+    StatusCode,
+};
+use std::{
+    error::Error,
+    io::Read,
+    sync::{Condvar, Mutex},
+    thread,
+    time::Instant,
+};
+
+/// Error of a request or of reading its body.
+pub type FetchError = Box<dyn Error + Send + Sync>;
+
+/// Bytes of a body read at once, and accounted to the rate limit.
+const CHUNK: usize = 64 * 1024;
+
+/// Body of a ranged GET together with the full file length, if the server told it.
+pub struct Fetched {
+    pub data: Vec<u8>,
+    pub total: Option<u64>,
+}
+
+/// Parse the complete length out of `Content-Range: bytes 0-99/1234`
+/// or `Content-Range: bytes */1234`.
+pub fn content_range_total(header: &str) -> Option<u64> {
+    let spec = header.trim().strip_prefix("bytes")?.trim();
+    let (_, total) = spec.split_at(spec.rfind('/')?);
+    total[1..].trim().parse::<u64>().ok()
+}
+
+/// GET `range` of `url`. A range past the end of file yields no data.
+pub fn fetch_range(client: &Client, url: &str, range: Range) -> Result<Fetched, FetchError> {
+    let response;
+    unsafe {
+        response = client
+            .get(url)
+            .header(RANGE, range.header())
+            .header("Authorization", format!("Basic {}", HTTP_AUTH))
+            .send()?;
+        METRICS.lock().unwrap().http_requests += 1;
+    }
+    let total = response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|header| header.to_str().ok())
+        .and_then(content_range_total);
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        return Ok(Fetched {
+            data: Vec::new(),
+            total,
+        });
+    }
+    let data = read_body(response.error_for_status()?)?;
+    Ok(Fetched { data, total })
+}
+
+/// Whole body of `response`, read chunk by chunk to keep within the rate.
+pub fn read_body(mut response: Response) -> Result<Vec<u8>, FetchError> {
+    let mut data = Vec::new();
+    let mut buffer = vec![0; CHUNK];
+    loop {
+        let n = response.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&buffer[..n]);
+        limit::consumed(n as u64);
+    }
+}
+
+/// Whether `err` says there is nothing at the URL, as opposed to a failure.
+pub fn not_found(err: &FetchError) -> bool {
+    let status = err
+        .downcast_ref::<reqwest::Error>()
+        .and_then(|err| err.status());
+    matches!(status, Some(StatusCode::NOT_FOUND) | Some(StatusCode::GONE))
+}
+
+/// GET the whole of `url`.
+pub fn fetch(client: &Client, url: &str) -> Result<Vec<u8>, FetchError> {
+    let response;
+    unsafe {
+        response = client
+            .get(url)
+            .header("Authorization", format!("Basic {}", HTTP_AUTH))
+            .send()?;
+        METRICS.lock().unwrap().http_requests += 1;
+    }
+    read_body(response.error_for_status()?)
+}
+
+/// Connection limits of background downloads: how many pieces of one file are
+/// fetched at once, and how many connections all downloads may use together.
+pub struct Connections {
+    per_file: usize,
+    max: usize,
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A connection taken from `Connections`, given back on drop.
+pub struct Slot<'a>(&'a Connections);
+
+impl Connections {
+    pub fn new(per_file: usize, max: usize) -> Connections {
+        Connections {
+            per_file: per_file.max(1),
+            max: max.max(1),
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Wait for a free connection.
+    pub fn acquire(&self) -> Slot<'_> {
+        let mut used = self.used.lock().unwrap();
+        while *used >= self.max {
+            used = self.freed.wait(used).unwrap();
+        }
+        *used += 1;
+        METRICS.lock().unwrap().active_connections = *used as u64;
+        Slot(self)
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut used = self.0.used.lock().unwrap();
+        *used -= 1;
+        METRICS.lock().unwrap().active_connections = *used as u64;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Split `range` into pieces of `piece_size` bytes.
+pub fn pieces(range: Range, piece_size: u64) -> Vec<Range> {
+    let piece_size = piece_size.max(1);
+    let mut pieces = Vec::new();
+    let mut start = range.start;
+    while start < range.end {
+        let end = (start + piece_size).min(range.end);
+        pieces.push(Range { start, end });
+        start = end;
+    }
+    pieces
+}
+
+/// GET `range` of `url` as several concurrent requests of `piece_size` bytes.
+/// Pieces are handed to `sink` in order, it returns false to stop early.
+///
+/// The first piece is fetched alone to learn the real file length, which
+/// may differ from the assumed `length`. Then `range` is cut at the real end
+/// of file, or follows it if `range` was to reach the end.
+pub fn fetch_parallel<F>(
+    client: &Client,
+    url: &str,
+    range: Range,
+    length: u64,
+    piece_size: u64,
+    connections: &Connections,
+    mut sink: F,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(Range, Fetched) -> Result<bool, Box<dyn Error>>,
+{
+    let mut range = range;
+    let mut start = range.start;
+    let mut first = true;
+    while start < range.end {
+        if let Some(quota) = limit::exhausted() {
+            return Err(format!("The {} quota is exhausted", quota).into());
+        }
+        let count = if first { 1 } else { connections.per_file };
+        let window: Vec<Range> = pieces(
+            Range {
+                start,
+                end: range.end,
+            },
+            piece_size,
+        )
+        .into_iter()
+        .take(count)
+        .collect();
+        start = window.last().unwrap().end;
+        let results: Vec<Result<Fetched, FetchError>> = thread::scope(|scope| {
+            let handles: Vec<_> = window
+                .iter()
+                .map(|&piece| {
+                    scope.spawn(move || {
+                        let _slot = connections.acquire();
+                        let started = Instant::now();
+                        let fetched = fetch_range(client, url, piece)?;
+                        record_throughput(fetched.data.len() as u64, started);
+                        Ok(fetched)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        for (&piece, result) in window.iter().zip(results) {
+            let fetched = result.map_err(|e| e.to_string())?;
+            if let (true, Some(total)) = (first, fetched.total) {
+                let end = if range.end >= length {
+                    total
+                } else {
+                    range.end.min(total)
+                };
+                if end != range.end {
+                    warn!("{} is {} bytes long, not {}", url, total, length);
+                    range.end = end;
+                }
+            }
+            first = false;
+            let piece = Range {
+                start: piece.start,
+                end: piece.end.min(range.end),
+            };
+            if fetched.data.len() as u64 != piece.len() {
+                return Err(format!(
+                    "Got {} bytes for range {}, expected {}",
+                    fetched.data.len(),
+                    piece.header(),
+                    piece.len()
+                )
+                .into());
+            }
+            if !sink(piece, fetched)? {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fold throughput of a finished connection into a moving average.
+fn record_throughput(bytes: u64, started: Instant) {
+    let millis = (started.elapsed().as_millis() as u64).max(1);
+    let throughput = bytes * 1000 / millis;
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.connection_throughput = if metrics.connection_throughput == 0 {
+        throughput
+    } else {
+        (metrics.connection_throughput * 7 + throughput) / 8
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_range() {
+        assert_eq!(content_range_total("bytes 0-99/1234"), Some(1234));
+        assert_eq!(content_range_total("bytes */6442450944"), Some(6442450944));
+        assert_eq!(content_range_total("bytes 0-99/*"), None);
+        assert_eq!(content_range_total("items 0-99/1234"), None);
+    }
+
+    #[test]
+    fn split_pieces() {
+        let r = |start, end| Range { start, end };
+        assert_eq!(pieces(r(5, 25), 10), vec![r(5, 15), r(15, 25)]);
+        assert_eq!(pieces(r(0, 21), 10), vec![r(0, 10), r(10, 20), r(20, 21)]);
+        assert_eq!(pieces(r(7, 7), 10), vec![]);
+    }
+}
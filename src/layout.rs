@@ -0,0 +1,386 @@
+use crate::{
+    get_basename,
+    template::{self, Template},
+    text, Track,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
+
+/// How to name tracks whose basenames collide with each other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Disambiguation {
+    /// `01 - Intro (Artist).mp3`
+    Artist,
+    /// `01 - Intro (Album).mp3`
+    Album,
+    /// `01 - Intro (id).mp3`
+    Id,
+    /// Put every track into `Artist/Album/` directories.
+    Hierarchy,
+}
+
+impl FromStr for Disambiguation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "artist" => Ok(Disambiguation::Artist),
+            "album" => Ok(Disambiguation::Album),
+            "id" => Ok(Disambiguation::Id),
+            "hierarchy" => Ok(Disambiguation::Hierarchy),
+            _ => Err(format!(
+                "Unknown name collision strategy '{}'. Use artist, album, id or hierarchy.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Disambiguation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Disambiguation::Artist => "artist",
+            Disambiguation::Album => "album",
+            Disambiguation::Id => "id",
+            Disambiguation::Hierarchy => "hierarchy",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Result of laying out the library: one path (list of components) per track.
+pub struct Layout {
+    pub paths: Vec<Vec<String>>,
+    pub collisions: u64,
+    // Path of a renamed file or directory -> its original name.
+    pub originals: HashMap<Vec<String>, String>,
+}
+
+/// Insert `suffix` between file stem and extension: `a.mp3` -> `a (suffix).mp3`.
+pub fn with_suffix(name: &str, suffix: &str) -> String {
+    let suffix = template::sanitize(suffix);
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{} ({}){}", &name[..dot], suffix, &name[dot..]),
+        _ => format!("{} ({})", name, suffix),
+    }
+}
+
+/// Metadata value usable as a directory name, `default` if there is none.
+fn dir_name(value: Option<&String>, default: &str) -> String {
+    let name = template::sanitize(value.map_or("", |value| value.trim()));
+    match name.as_str() {
+        "" | "." | ".." => default.to_string(),
+        _ => name,
+    }
+}
+
+fn suffix_for(track: &Track, strategy: Disambiguation) -> Option<String> {
+    match strategy {
+        Disambiguation::Artist => track.artist.clone(),
+        Disambiguation::Album => track.album.clone(),
+        Disambiguation::Id | Disambiguation::Hierarchy => track.id.clone(),
+    }
+    .filter(|s| !s.is_empty())
+}
+
+/// Build a path for every track so that no two tracks end up under the same name.
+///
+/// Paths come from `template` if there is one. With `safe` every component is
+/// made acceptable to FAT32 and Windows. Tracks sharing a name within one
+/// directory are renamed using the chosen strategy first, then by id, then by
+/// a sequence number as the last resort.
+pub fn layout(
+    tracks: &[Track],
+    strategy: Disambiguation,
+    template: Option<&Template>,
+    safe: bool,
+) -> Layout {
+    let mut paths: Vec<Vec<String>> = tracks
+        .iter()
+        .map(|track| {
+            if let Some(template) = template {
+                return template.render(track);
+            }
+            let basename = get_basename(track.path.as_ref()).unwrap_or_default();
+            match strategy {
+                Disambiguation::Hierarchy => vec![
+                    dir_name(track.artist.as_ref(), "Unknown Artist"),
+                    dir_name(track.album.as_ref(), "Unknown Album"),
+                    basename,
+                ],
+                _ => vec![basename],
+            }
+        })
+        .collect();
+
+    let original = paths.clone();
+    if safe {
+        for name in paths.iter_mut().flatten() {
+            *name = text::safe_name(name);
+        }
+    }
+
+    // A file can't take the name of a directory.
+    let dirs: HashSet<Vec<String>> = paths
+        .iter()
+        .flat_map(|path| (1..path.len()).map(move |n| path[..n].to_vec()))
+        .collect();
+    for (i, path) in paths.iter_mut().enumerate() {
+        if dirs.contains(path) {
+            let name = path.last_mut().unwrap();
+            *name = with_suffix(name, tracks[i].id.as_deref().unwrap_or("file"));
+        }
+    }
+
+    let plain = paths.clone();
+    let mut collisions = 0;
+    let rounds = [Some(strategy), Some(Disambiguation::Id), None];
+    for round in rounds.iter() {
+        let mut groups: HashMap<&[String], Vec<usize>> = HashMap::new();
+        for (i, path) in paths.iter().enumerate() {
+            groups.entry(path.as_slice()).or_default().push(i);
+        }
+        let clashing: Vec<Vec<usize>> = groups
+            .into_values()
+            .filter(|members| members.len() > 1)
+            .collect();
+        if clashing.is_empty() {
+            break;
+        }
+        for members in clashing {
+            if round == &Some(strategy) {
+                warn!(
+                    "Name collision: {} tracks share the name {}",
+                    members.len(),
+                    paths[members[0]].join("/")
+                );
+                collisions += members.len() as u64;
+            }
+            // A track keeping its own name stays as it is, tracks suffixed
+            // before which now take it get another suffix.
+            let renamed: Vec<usize> = members
+                .iter()
+                .copied()
+                .filter(|&i| paths[i] != plain[i])
+                .collect();
+            let members = if renamed.is_empty() { members } else { renamed };
+            for (n, &i) in members.iter().enumerate() {
+                let suffix = match round {
+                    Some(round) => suffix_for(&tracks[i], *round),
+                    None => Some((n + 1).to_string()),
+                };
+                if let Some(suffix) = suffix {
+                    let name = paths[i].last_mut().unwrap();
+                    *name = with_suffix(name, &suffix);
+                }
+            }
+        }
+    }
+    // Suffixed names may still equal names of other tracks or ones suffixed
+    // in another round. Tracks keeping their own names go first, the others
+    // get numbers until they are unique.
+    let mut taken = dirs;
+    let mut order: Vec<usize> = (0..paths.len()).collect();
+    order.sort_by_key(|&i| paths[i] != plain[i]);
+    for i in order {
+        if taken.contains(&paths[i]) {
+            let base = paths[i].last().unwrap().clone();
+            let mut n = 2;
+            loop {
+                *paths[i].last_mut().unwrap() = with_suffix(&base, &n.to_string());
+                if !taken.contains(&paths[i]) {
+                    break;
+                }
+                n += 1;
+            }
+        }
+        taken.insert(paths[i].clone());
+    }
+    let mut originals = HashMap::new();
+    if safe {
+        for (path, original) in paths.iter_mut().zip(original) {
+            if let Some(name) = path.last_mut() {
+                // Suffixes may have made it too long again.
+                *name = text::truncate_name(name);
+            }
+            for (n, name) in original.into_iter().enumerate() {
+                if path[n] != name {
+                    originals.insert(path[..=n].to_vec(), name);
+                }
+            }
+        }
+    }
+    Layout {
+        paths,
+        collisions,
+        originals,
+    }
+}
+
+/// Map every track to the one following it on the same album, ordered by
+/// disc and track number, then by name. Albums are told apart by artist, so
+/// all the "Greatest Hits" don't make one.
+pub fn next_in_album(tracks: &[Track], paths: &[Vec<String>]) -> HashMap<usize, usize> {
+    let mut albums: HashMap<(Option<&str>, &str), Vec<usize>> = HashMap::new();
+    for (i, track) in tracks.iter().enumerate() {
+        if let Some(album) = track.album.as_deref().filter(|album| !album.is_empty()) {
+            let artist = track.album_artist.as_deref().or(track.artist.as_deref());
+            albums.entry((artist, album)).or_default().push(i);
+        }
+    }
+    let mut next = HashMap::new();
+    for members in albums.values_mut() {
+        members.sort_by_key(|&i| {
+            (
+                tracks[i].disc_number,
+                tracks[i].track_number,
+                paths[i].last(),
+            )
+        });
+        for pair in members.windows(2) {
+            next.insert(pair[0], pair[1]);
+        }
+    }
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: &str, path: &str, artist: &str, album: &str) -> Track {
+        Track {
+            id: Some(id.to_string()),
+            path: Some(path.to_string()),
+            artist: Some(artist.to_string()),
+            album: Some(album.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn clashing() -> Vec<Track> {
+        vec![
+            track("1", "/a/01 - Intro.mp3", "AC/DC", "Live"),
+            track("2", "/b/01 - Intro.mp3", "Björk", "Debut"),
+            track("3", "/c/02 - Song.mp3", "Björk", "Post"),
+        ]
+    }
+
+    fn names(strategy: Disambiguation) -> Vec<String> {
+        layout(&clashing(), strategy, None, false)
+            .paths
+            .iter()
+            .map(|path| path.join("/"))
+            .collect()
+    }
+
+    #[test]
+    fn strategies() {
+        assert_eq!(
+            names(Disambiguation::Artist),
+            vec![
+                "01 - Intro (AC_DC).mp3",
+                "01 - Intro (Björk).mp3",
+                "02 - Song.mp3"
+            ]
+        );
+        assert_eq!(
+            names(Disambiguation::Album),
+            vec![
+                "01 - Intro (Live).mp3",
+                "01 - Intro (Debut).mp3",
+                "02 - Song.mp3"
+            ]
+        );
+        assert_eq!(
+            names(Disambiguation::Id),
+            vec!["01 - Intro (1).mp3", "01 - Intro (2).mp3", "02 - Song.mp3"]
+        );
+        assert_eq!(
+            names(Disambiguation::Hierarchy),
+            vec![
+                "AC_DC/Live/01 - Intro.mp3",
+                "Björk/Debut/01 - Intro.mp3",
+                "Björk/Post/02 - Song.mp3"
+            ]
+        );
+        assert_eq!(
+            layout(&clashing(), Disambiguation::Album, None, false).collisions,
+            2
+        );
+    }
+
+    #[test]
+    fn fallbacks() {
+        // Same artist for both: album, then id, then a number.
+        let tracks = vec![
+            track("1", "/a/x.mp3", "A", "B"),
+            track("1", "/b/x.mp3", "A", "B"),
+        ];
+        let layout = layout(&tracks, Disambiguation::Artist, None, false);
+        assert_eq!(
+            layout.paths,
+            vec![vec!["x (A) (1) (1).mp3"], vec!["x (A) (1) (2).mp3"]]
+        );
+    }
+
+    #[test]
+    fn taken_suffixes() {
+        // Numbered names must not land on a track which is named so.
+        let tracks = vec![
+            track("1", "/a/x.mp3", "A", "B"),
+            track("2", "/b/x.mp3", "A", "B"),
+            track("3", "/c/x (1).mp3", "A", "B"),
+            track("4", "/d/x (1) (1).mp3", "A", "B"),
+            track("5", "/e/x (1) (1) (1).mp3", "A", "B"),
+        ];
+        let layout = layout(&tracks, Disambiguation::Id, None, false);
+        assert_eq!(
+            layout.paths,
+            vec![
+                vec!["x (1) (1) (1) (2).mp3"],
+                vec!["x (2).mp3"],
+                vec!["x (1).mp3"],
+                vec!["x (1) (1).mp3"],
+                vec!["x (1) (1) (1).mp3"]
+            ]
+        );
+    }
+
+    #[test]
+    fn albums() {
+        let mut tracks = vec![
+            track("1", "/a/2.mp3", "A", "Greatest Hits"),
+            track("2", "/b/1.mp3", "B", "Greatest Hits"),
+            track("3", "/a/1.mp3", "A", "Greatest Hits"),
+            track("4", "/b/2.mp3", "C", "Greatest Hits"),
+        ];
+        tracks[3].album_artist = Some("B".to_string());
+        let paths: Vec<Vec<String>> = tracks
+            .iter()
+            .map(|track| vec![track.path.clone().unwrap()])
+            .collect();
+        let next = next_in_album(&tracks, &paths);
+        assert_eq!(next.len(), 2);
+        assert_eq!((next[&2], next[&1]), (0, 3));
+    }
+
+    #[test]
+    fn hierarchy_names() {
+        let tracks = vec![
+            track("1", "/a/x.mp3", "..", "a\0b"),
+            track("2", "/a/y.mp3", " ", "."),
+        ];
+        let layout = layout(&tracks, Disambiguation::Hierarchy, None, false);
+        assert_eq!(
+            layout.paths,
+            vec![
+                vec!["Unknown Artist", "a_b", "x.mp3"],
+                vec!["Unknown Artist", "Unknown Album", "y.mp3"]
+            ]
+        );
+    }
+}
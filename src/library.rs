@@ -0,0 +1,303 @@
+use crate::{get_basename, Track};
+use chrono::DateTime;
+use serde_json::{Map, Value};
+use std::{convert::TryFrom, fmt};
+
+/// A library entry that couldn't be turned into a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejected {
+    pub index: usize,
+    pub reason: String,
+    pub entry: String,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}: {} {}", self.index, self.reason, self.entry)
+    }
+}
+
+/// Longest piece of a rejected entry kept in the report.
+const ENTRY_EXCERPT: usize = 200;
+
+fn excerpt(value: &Value) -> String {
+    let mut entry = value.to_string();
+    if entry.len() > ENTRY_EXCERPT {
+        let mut end = ENTRY_EXCERPT;
+        while !entry.is_char_boundary(end) {
+            end -= 1;
+        }
+        entry.truncate(end);
+        entry.push_str("...");
+    }
+    entry
+}
+
+/// Read fields one by one, dropping the ones of unexpected type.
+fn lenient_track(obj: &Map<String, Value>) -> Track {
+    let string = |key: &str| match obj.get(key) {
+        Some(Value::String(s)) => Some(s.clone()),
+        Some(Value::Number(n)) => Some(n.to_string()),
+        _ => None,
+    };
+    let int = |key: &str| match obj.get(key) {
+        Some(Value::Number(n)) => n.as_i64(),
+        Some(Value::String(s)) => s.trim().parse::<i64>().ok(),
+        _ => None,
+    };
+    let first = |keys: &[&'static str]| -> Option<&'static str> {
+        keys.iter().find(|key| obj.contains_key(**key)).copied()
+    };
+    // Track and disc numbers come as `3` or `3/12`.
+    let number = |keys: &[&'static str]| match obj.get(first(keys)?) {
+        Some(Value::Number(n)) => n.as_u64().and_then(|n| u32::try_from(n).ok()),
+        Some(Value::String(s)) => s.split('/').next()?.trim().parse::<u32>().ok(),
+        _ => None,
+    };
+    let duration = match obj.get(first(&["duration", "length"]).unwrap_or("")) {
+        Some(Value::Number(n)) => n.as_f64(),
+        Some(Value::String(s)) => s.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+    .filter(|duration| duration.is_finite() && *duration >= 0.0);
+    // Unix time in seconds or milliseconds, or RFC 3339.
+    let modified = match obj.get(first(&["modified", "mtime"]).unwrap_or("")) {
+        Some(Value::String(s)) => DateTime::parse_from_rfc3339(s.trim())
+            .map(|time| time.timestamp())
+            .ok()
+            .or_else(|| s.trim().parse::<i64>().ok()),
+        Some(Value::Number(n)) => n.as_i64(),
+        _ => None,
+    }
+    .map(|time| {
+        if time > 100_000_000_000 {
+            time / 1000
+        } else {
+            time
+        }
+    });
+    Track {
+        id: string("id"),
+        name: string("name"),
+        artist: string("artist"),
+        album: string("album"),
+        genre: string("genre"),
+        year: int("year").and_then(|year| i32::try_from(year).ok()),
+        format: string("format"),
+        filetype: string("filetype"),
+        path: string("path"),
+        size: int("size"),
+        track_number: number(&["track_number", "tracknumber", "track"]),
+        disc_number: number(&["disc_number", "discnumber", "disc"]),
+        duration,
+        album_artist: first(&["album_artist", "albumartist"]).and_then(string),
+        bitrate: number(&["bitrate"]),
+        modified,
+    }
+}
+
+/// Turn a raw library entry into a `Track` usable by the filesystem.
+pub fn parse_track(value: &Value) -> Result<Track, String> {
+    let obj = match value {
+        Value::Object(obj) => obj,
+        _ => return Err("not an object".to_string()),
+    };
+    let lenient = lenient_track(obj);
+    let mut track = match serde_json::from_value::<Track>(value.clone()) {
+        Ok(track) => Track {
+            track_number: lenient.track_number,
+            disc_number: lenient.disc_number,
+            duration: lenient.duration,
+            album_artist: lenient.album_artist,
+            bitrate: lenient.bitrate,
+            modified: lenient.modified,
+            ..track
+        },
+        Err(e) => {
+            debug!("Falling back to lenient parsing: {}", e);
+            lenient
+        }
+    };
+    let path = match &track.path {
+        Some(path) if !path.is_empty() => path.clone(),
+        _ => return Err("missing path".to_string()),
+    };
+    match get_basename(Some(&path)) {
+        Some(name) if !name.is_empty() => (),
+        _ => return Err("path has no usable file name".to_string()),
+    }
+    match track.size {
+        Some(size) if size >= 0 => (),
+        Some(_) => return Err("negative size".to_string()),
+        None => return Err("missing size".to_string()),
+    }
+    if track.id.as_deref().unwrap_or("").is_empty() {
+        track.id = Some(path);
+    }
+    Ok(track)
+}
+
+/// Split the `/songs` payload into usable tracks and rejected entries.
+pub fn parse_tracks(values: &[Value]) -> (Vec<Track>, Vec<Rejected>) {
+    let mut tracks = Vec::with_capacity(values.len());
+    let mut rejected = Vec::new();
+    for (index, value) in values.iter().enumerate() {
+        match parse_track(value) {
+            Ok(track) => tracks.push(track),
+            Err(reason) => {
+                let entry = Rejected {
+                    index,
+                    reason,
+                    entry: excerpt(value),
+                };
+                warn!("Skipping library entry {}", entry);
+                rejected.push(entry);
+            }
+        }
+    }
+    (tracks, rejected)
+}
+
+/// Contents of `REJECTED.TXT`.
+pub fn rejected_report(rejected: &[Rejected]) -> String {
+    let mut report = format!("rejected: {}\n", rejected.len());
+    for entry in rejected {
+        report.push_str(&entry.to_string());
+        report.push('\n');
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{JsonFilesystem, Options, Prefetch};
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    fn arb_json() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            any::<f64>().prop_map(Value::from),
+            ".*".prop_map(Value::from),
+        ];
+        leaf.prop_recursive(3, 32, 6, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..6).prop_map(Value::from),
+                prop::collection::hash_map(".*", inner, 0..6)
+                    .prop_map(|m| Value::Object(m.into_iter().collect())),
+            ]
+        })
+    }
+
+    fn arb_field() -> impl Strategy<Value = Value> {
+        prop_oneof![
+            Just(Value::Null),
+            any::<i64>().prop_map(Value::from),
+            "[0-9]{1,6}".prop_map(Value::from),
+            "(/[a-zA-Z0-9 %._-]{0,12}){0,3}".prop_map(Value::from),
+            ".*".prop_map(Value::from),
+            arb_json(),
+        ]
+    }
+
+    /// Objects that look like tracks, with every field possibly missing or mistyped.
+    fn arb_track() -> impl Strategy<Value = Value> {
+        let keys = [
+            "id", "name", "artist", "album", "genre", "year", "format", "filetype", "path", "size",
+        ];
+        prop::collection::vec(prop::option::of(arb_field()), keys.len()).prop_map(move |fields| {
+            let mut obj = Map::new();
+            for (key, field) in keys.iter().zip(fields) {
+                if let Some(field) = field {
+                    obj.insert(key.to_string(), field);
+                }
+            }
+            Value::Object(obj)
+        })
+    }
+
+    fn arb_library() -> impl Strategy<Value = Vec<Value>> {
+        prop::collection::vec(prop_oneof![4 => arb_track(), 1 => arb_json()], 0..24)
+    }
+
+    #[test]
+    fn fallbacks() {
+        let track = parse_track(&json!({"path": "/a/b.mp3", "size": "12", "year": "x"})).unwrap();
+        assert_eq!(track.id.as_deref(), Some("/a/b.mp3"));
+        assert_eq!(track.size, Some(12));
+        assert_eq!(track.year, None);
+        assert!(parse_track(&json!({"path": "/a/b.mp3"})).is_err());
+        assert!(parse_track(&json!({"size": 1})).is_err());
+        assert!(parse_track(&json!({"path": "/", "size": 1})).is_err());
+        assert!(parse_track(&json!({"path": "/a.mp3", "size": -1})).is_err());
+        assert!(parse_track(&json!([1, 2])).is_err());
+        let track = parse_track(&json!({
+            "path": "/a.mp3",
+            "size": 1,
+            "track": "3/12",
+            "discnumber": 2,
+            "duration": "215.5",
+            "albumartist": "Various",
+            "bitrate": 320,
+            "modified": "2020-04-27T10:00:00Z",
+        }))
+        .unwrap();
+        assert_eq!(track.track_number, Some(3));
+        assert_eq!(track.disc_number, Some(2));
+        assert_eq!(track.duration, Some(215.5));
+        assert_eq!(track.album_artist.as_deref(), Some("Various"));
+        assert_eq!(track.bitrate, Some(320));
+        assert_eq!(track.modified, Some(1587981600));
+        let track = parse_track(&json!({"path": "/a.mp3", "size": 1, "mtime": 1587981600000i64}));
+        assert_eq!(track.unwrap().modified, Some(1587981600));
+    }
+
+    proptest! {
+        #[test]
+        fn track_deserialization_never_panics(value in arb_json()) {
+            let _ = serde_json::from_value::<Track>(value.clone());
+            let _ = parse_track(&value);
+        }
+
+        #[test]
+        fn accepted_tracks_are_usable(values in arb_library()) {
+            let (tracks, rejected) = parse_tracks(&values);
+            prop_assert_eq!(tracks.len() + rejected.len(), values.len());
+            for track in &tracks {
+                prop_assert!(track.id.is_some());
+                prop_assert!(track.size.unwrap() >= 0);
+                prop_assert!(get_basename(track.path.as_ref()).is_some());
+            }
+        }
+
+        #[test]
+        fn filesystem_builds_from_any_library(values in arb_library()) {
+            let (tracks, rejected) = parse_tracks(&values);
+            let fs = JsonFilesystem::new(
+                &tracks,
+                rejected,
+                &[],
+                &[],
+                String::new(),
+                Options {
+                    prefetch: Prefetch::Off,
+                    ..Options::default()
+                },
+            );
+            for ino in 2..2 + tracks.len() as u64 {
+                prop_assert!(fs.attrs.contains_key(&ino));
+                prop_assert!(fs.parents.contains_key(&ino));
+            }
+            let reachable = fs
+                .inodes
+                .values()
+                .flat_map(|entries| entries.values())
+                .filter(|&&ino| ino >= 2 && ino < 2 + tracks.len() as u64)
+                .count();
+            prop_assert_eq!(reachable, tracks.len());
+        }
+    }
+}
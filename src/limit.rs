@@ -0,0 +1,343 @@
+//! Bandwidth limit and ingress quotas shared by all downloads. Quotas start
+//! over each day and month, and are kept in `limits.json` of the cache
+//! directory so remounting doesn't reset them.
+
+use crate::METRICS;
+use chrono::{Datelike, Local};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+const LIMITS_FILE: &str = "limits.json";
+/// Bytes received between saves of the quota counters.
+const SAVE_EVERY: u64 = 16 * 1024 * 1024;
+/// Longest the thread serving the filesystem sleeps for a chunk, every other
+/// file operation waits meanwhile.
+const MAX_FOREGROUND_WAIT: Duration = Duration::from_secs(1);
+
+thread_local! {
+    // Set on the thread serving the filesystem.
+    static FOREGROUND: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Quota counters of the current day and month.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+struct Usage {
+    day: (i32, u32),
+    today: u64,
+    month: (i32, u32),
+    this_month: u64,
+}
+
+pub struct Limits {
+    /// Bytes per second, 0 for no limit.
+    rate: u64,
+    /// Bytes per day and per month, 0 for no quota.
+    daily: u64,
+    monthly: u64,
+    // Token bucket, negative when downloads are ahead of the rate.
+    tokens: f64,
+    refilled: Option<Instant>,
+    usage: Usage,
+    // Where `usage` is saved, and bytes counted since the last save.
+    file: Option<PathBuf>,
+    unsaved: u64,
+}
+
+pub static LIMITS: Mutex<Limits> = Mutex::new(Limits {
+    rate: 0,
+    daily: 0,
+    monthly: 0,
+    tokens: 0.0,
+    refilled: None,
+    usage: Usage {
+        day: (0, 0),
+        today: 0,
+        month: (0, 0),
+        this_month: 0,
+    },
+    file: None,
+    unsaved: 0,
+});
+
+/// Parse sizes like `512`, `64K`, `10M` or `1G`. `off` and `0` mean no limit.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    if value == "off" {
+        return Ok(0);
+    }
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, ""),
+    };
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err(format!("Unknown size unit in '{}'", value)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("Wrong size '{}'", value))
+}
+
+impl Limits {
+    /// Apply a control line: `rate 512K`, `daily 1G` or `monthly off`.
+    pub fn apply(&mut self, line: &str) -> Result<(), String> {
+        let mut words = line.split_whitespace();
+        let (name, value) = match (words.next(), words.next(), words.next()) {
+            (Some(name), Some(value), None) => (name, parse_size(value)?),
+            _ => return Err(format!("Expected '<limit> <size>', got '{}'", line)),
+        };
+        match name {
+            "rate" => self.rate = value,
+            "daily" => self.daily = value,
+            "monthly" => self.monthly = value,
+            _ => {
+                return Err(format!(
+                    "Unknown limit '{}'. Use rate, daily or monthly.",
+                    name
+                ))
+            }
+        }
+        info!("Limit {} set to {}", name, value);
+        Ok(())
+    }
+
+    /// Start counting quotas over when a new day or month begins.
+    fn roll(&mut self) {
+        let now = Local::now();
+        let day = (now.year(), now.ordinal());
+        let month = (now.year(), now.month());
+        if self.usage.day != day {
+            self.usage.day = day;
+            self.usage.today = 0;
+        }
+        if self.usage.month != month {
+            self.usage.month = month;
+            self.usage.this_month = 0;
+        }
+    }
+
+    fn count(&mut self, bytes: u64) {
+        self.roll();
+        self.usage.today += bytes;
+        self.usage.this_month += bytes;
+        self.unsaved += bytes;
+        if self.unsaved >= SAVE_EVERY {
+            self.save();
+        }
+    }
+
+    fn save(&mut self) {
+        self.unsaved = 0;
+        if let Some(file) = &self.file {
+            if let Err(e) = save(file, &self.usage) {
+                error!("Can't save quota counters to {}. {}", file.display(), e);
+            }
+        }
+    }
+
+    /// Name of the quota which is used up, if any.
+    fn exhausted(&mut self) -> Option<&'static str> {
+        self.roll();
+        if self.daily > 0 && self.usage.today >= self.daily {
+            Some("daily")
+        } else if self.monthly > 0 && self.usage.this_month >= self.monthly {
+            Some("monthly")
+        } else {
+            None
+        }
+    }
+
+    /// Take `bytes` out of the bucket, returning how long to wait for them.
+    /// The `foreground` waits only for its own bytes, not for what background
+    /// downloads took before, and at most `MAX_FOREGROUND_WAIT`.
+    fn take(&mut self, bytes: u64, now: Instant, foreground: bool) -> Duration {
+        if self.rate == 0 {
+            self.refilled = None;
+            return Duration::from_secs(0);
+        }
+        let rate = self.rate as f64;
+        let elapsed = self
+            .refilled
+            .map_or(1.0, |refilled| (now - refilled).as_secs_f64());
+        // At most one second worth of burst.
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - bytes as f64;
+        self.refilled = Some(now);
+        if self.tokens >= 0.0 {
+            return Duration::from_secs(0);
+        }
+        let debt = if foreground {
+            (-self.tokens).min(bytes as f64)
+        } else {
+            -self.tokens
+        };
+        let wait = Duration::from_secs_f64(debt / rate);
+        if foreground {
+            wait.min(MAX_FOREGROUND_WAIT)
+        } else {
+            wait
+        }
+    }
+}
+
+fn save(file: &Path, usage: &Usage) -> io::Result<()> {
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temp = file.with_extension("tmp");
+    fs::write(&temp, serde_json::to_vec(usage)?)?;
+    fs::rename(temp, file)
+}
+
+/// Keep quota counters in `dir`, starting from the ones saved there.
+pub fn persist(dir: &Path) {
+    let file = dir.join(LIMITS_FILE);
+    let mut limits = LIMITS.lock().unwrap();
+    if let Ok(data) = fs::read(&file) {
+        match serde_json::from_slice(&data) {
+            Ok(usage) => limits.usage = usage,
+            Err(e) => warn!("Ignoring broken {}. {}", file.display(), e),
+        }
+    }
+    limits.file = Some(file);
+}
+
+/// Save quota counters, on unmount.
+pub fn flush() {
+    LIMITS.lock().unwrap().save();
+}
+
+/// Mark the current thread as the one serving the filesystem. Its downloads
+/// are held to the rate too, but it goes first: background downloads wait for
+/// its bytes, it never waits for theirs.
+pub fn foreground() {
+    FOREGROUND.with(|foreground| foreground.set(true));
+}
+
+/// Account `bytes` received from the server, sleeping to keep within the
+/// rate. Bodies are read in small chunks, so this shapes the wire rate.
+pub fn consumed(bytes: u64) {
+    METRICS.lock().unwrap().ingress += bytes;
+    let wait = {
+        let mut limits = LIMITS.lock().unwrap();
+        limits.count(bytes);
+        limits.take(bytes, Instant::now(), FOREGROUND.with(Cell::get))
+    };
+    if wait > Duration::from_secs(0) {
+        thread::sleep(wait);
+    }
+}
+
+/// Name of the quota which is used up, if any. No new data must be fetched then.
+pub fn exhausted() -> Option<&'static str> {
+    LIMITS.lock().unwrap().exhausted()
+}
+
+/// Content of LIMITS.TXT.
+pub fn report() -> String {
+    let mut limits = LIMITS.lock().unwrap();
+    let state = limits.exhausted().unwrap_or("ok");
+    format!(
+        "rate {}\ndaily {}\nmonthly {}\n# used_today: {}\n# used_this_month: {}\n# quota: {}\n",
+        limits.rate,
+        limits.daily,
+        limits.monthly,
+        limits.usage.today,
+        limits.usage.this_month,
+        state
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            rate: 0,
+            daily: 0,
+            monthly: 0,
+            tokens: 0.0,
+            refilled: None,
+            usage: Usage::default(),
+            file: None,
+            unsaved: 0,
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64K"), Ok(64 << 10));
+        assert_eq!(parse_size(" 10 MiB "), Ok(10 << 20));
+        assert_eq!(parse_size("off"), Ok(0));
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
+
+    #[test]
+    fn control_lines() {
+        let mut limits = limits();
+        assert!(limits.apply("rate 1M").is_ok());
+        assert!(limits.apply("daily 2G").is_ok());
+        assert!(limits.apply("hourly 2G").is_err());
+        assert!(limits.apply("rate").is_err());
+        assert_eq!((limits.rate, limits.daily), (1 << 20, 2 << 30));
+        assert_eq!(limits.exhausted(), None);
+        limits.count(2 << 30);
+        assert_eq!(limits.exhausted(), Some("daily"));
+        assert!(limits.apply("daily off").is_ok());
+        assert_eq!(limits.exhausted(), None);
+    }
+
+    #[test]
+    fn new_day() {
+        let mut limits = limits();
+        limits.count(100);
+        limits.usage.day = (2000, 1);
+        limits.usage.month = (2000, 1);
+        limits.roll();
+        assert_eq!((limits.usage.today, limits.usage.this_month), (0, 0));
+    }
+
+    #[test]
+    fn token_bucket() {
+        let mut limits = limits();
+        let now = Instant::now();
+        assert_eq!(limits.take(1000, now, false), Duration::from_secs(0));
+        limits.rate = 1000;
+        // Burst of one second is free, the rest has to wait.
+        assert_eq!(limits.take(1000, now, false), Duration::from_secs(0));
+        assert_eq!(limits.take(500, now, false), Duration::from_millis(500));
+        let later = now + Duration::from_secs(2);
+        assert_eq!(limits.take(500, later, false), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn foreground_bucket() {
+        let mut limits = limits();
+        limits.rate = 1000;
+        let now = Instant::now();
+        limits.take(1000, now, false);
+        // Background went 2 s over, reads through the mount wait for their
+        // own bytes only.
+        assert_eq!(limits.take(2000, now, false), Duration::from_secs(2));
+        assert_eq!(limits.take(250, now, true), Duration::from_millis(250));
+        // And never long.
+        assert_eq!(limits.take(5000, now, true), MAX_FOREGROUND_WAIT);
+        // Background pays for them.
+        assert_eq!(limits.take(1000, now, false), Duration::from_millis(8250));
+    }
+}
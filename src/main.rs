@@ -1,3 +1,5 @@
+// Basic Auth string lives in a `static mut`, written once before mounting.
+#![allow(static_mut_refs)]
 extern crate base64;
 extern crate clap;
 extern crate fuse;
@@ -8,26 +10,64 @@ extern crate log;
 extern crate chrono;
 extern crate config;
 
+mod cache;
+mod cue;
+mod http;
+mod layout;
+mod library;
+mod limit;
+mod pin;
+mod playlist;
+mod prefetch;
+mod range;
+mod retag;
+mod scan;
+mod search;
+mod store;
+mod stream;
+mod tags;
+mod template;
+mod text;
+mod util;
+
+use cache::BlockCache;
 use clap::{App, Arg};
 use env_logger::Env;
 use fuse::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+    consts::FOPEN_DIRECT_IO, FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
 };
-use libc::{EIO, ENOENT};
-use percent_encoding::percent_decode_str;
-use reqwest::{blocking::Client, header::CONTENT_LENGTH};
+use http::Connections;
+use layout::Disambiguation;
+use libc::{c_int, EACCES, EDQUOT, EINVAL, EIO, ENODATA, ENOENT, ENOTSUP, ERANGE};
+use library::Rejected;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use playlist::{Selection, ServerPlaylist};
+use prefetch::Prefetch;
+use range::Range;
+use reqwest::{blocking::Client, header::CONTENT_LENGTH, StatusCode};
+use retag::Splice;
 use serde::Deserialize;
+use serde_json::Value;
 use size_format::SizeFormatterBinary;
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     env,
     ffi::OsStr,
     fmt,
-    path::Path,
+    path::{Path, PathBuf},
     process,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
+use store::Store;
+use stream::Stream;
+use tags::Tags;
+use template::Template;
 use time::Timespec;
 
 static mut HTTP_AUTH: String = String::new(); // Basic Auth string.
@@ -38,37 +78,73 @@ struct Metrics {
     ingress: u64,
     hit_len_cache: u64,
     hit_data_cache: u64,
+    hit_tail_cache: u64,
+    hit_disk_cache: u64,
     miss_len_cache: u64,
     miss_data_cache: u64,
+    miss_tail_cache: u64,
+    cache_bytes: u64,
+    disk_cache_bytes: u64,
+    name_collisions: u64,
+    rejected_tracks: u64,
+    size_mismatches: u64,
+    prefetches: u64,
+    active_connections: u64,
+    connection_throughput: u64,
+    streams_opened: u64,
     server_addr: String,
 }
 
 impl fmt::Debug for Metrics {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f,
-            "http_requests: {}\nconnect_errors: {}\ningress: {}\nhit_len_cache: {}\nhit_data_cache: {}\nmiss_len_cache: {}\nmiss_data_cache: {}\nserver_addr: {}\n", 
+            "http_requests: {}\nconnect_errors: {}\ningress: {}\nhit_len_cache: {}\nhit_data_cache: {}\nhit_tail_cache: {}\nhit_disk_cache: {}\nmiss_len_cache: {}\nmiss_data_cache: {}\nmiss_tail_cache: {}\ncache_bytes: {}\ndisk_cache_bytes: {}\nname_collisions: {}\nrejected_tracks: {}\nsize_mismatches: {}\nprefetches: {}\nactive_connections: {}\nconnection_throughput: {}\nstreams_opened: {}\nserver_addr: {}\n",
             self.http_requests,
             self.connect_errors,
             self.ingress,
             self.hit_len_cache,
             self.hit_data_cache,
+            self.hit_tail_cache,
+            self.hit_disk_cache,
             self.miss_len_cache,
             self.miss_data_cache,
+            self.miss_tail_cache,
+            self.cache_bytes,
+            self.disk_cache_bytes,
+            self.name_collisions,
+            self.rejected_tracks,
+            self.size_mismatches,
+            self.prefetches,
+            self.active_connections,
+            self.connection_throughput,
+            self.streams_opened,
             self.server_addr,
         )
     }
 }
 
-static mut METRICS: Metrics = Metrics {
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
     http_requests: 0,
     connect_errors: 0,
     ingress: 0,
     hit_len_cache: 0,
     hit_data_cache: 0,
+    hit_tail_cache: 0,
+    hit_disk_cache: 0,
     miss_len_cache: 0,
     miss_data_cache: 0,
+    miss_tail_cache: 0,
+    cache_bytes: 0,
+    disk_cache_bytes: 0,
+    name_collisions: 0,
+    rejected_tracks: 0,
+    size_mismatches: 0,
+    prefetches: 0,
+    active_connections: 0,
+    connection_throughput: 0,
+    streams_opened: 0,
     server_addr: String::new(),
-};
+});
 
 #[derive(Default, Debug, Clone, PartialEq, Deserialize)]
 pub struct Track {
@@ -82,17 +158,30 @@ pub struct Track {
     pub filetype: Option<String>,
     pub path: Option<String>,
     pub size: Option<i64>,
+    // Servers name and format these differently, see `library::lenient_track`.
+    #[serde(skip)]
+    pub track_number: Option<u32>,
+    #[serde(skip)]
+    pub disc_number: Option<u32>,
+    /// Seconds.
+    #[serde(skip)]
+    pub duration: Option<f64>,
+    #[serde(skip)]
+    pub album_artist: Option<String>,
+    /// Kbit/s.
+    #[serde(skip)]
+    pub bitrate: Option<u32>,
+    /// Unix time of the last change.
+    #[serde(skip)]
+    pub modified: Option<i64>,
 }
 
 fn get_basename(path: Option<&String>) -> Option<String> {
-    let base = match percent_decode_str(path.unwrap().as_str()).decode_utf8() {
+    let base = match percent_decode_str(path?.as_str()).decode_utf8() {
         Ok(path) => {
             let remote_name = path.into_owned();
             let basename = Path::new(&remote_name).file_name();
-            match basename {
-                Some(name) => Some(name.to_os_string().into_string().unwrap()),
-                None => None,
-            }
+            basename.map(|name| name.to_os_string().into_string().unwrap())
         }
         Err(_) => None,
     };
@@ -100,7 +189,7 @@ fn get_basename(path: Option<&String>) -> Option<String> {
 }
 
 #[tokio::main]
-async fn get_tracks(server: &String) -> Result<Vec<Track>, Box<dyn std::error::Error>> {
+async fn get_tracks(server: &str) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
     let client = reqwest::Client::new();
     unsafe {
         let resp = client
@@ -108,41 +197,331 @@ async fn get_tracks(server: &String) -> Result<Vec<Track>, Box<dyn std::error::E
             .header("Authorization", format!("Basic {}", HTTP_AUTH))
             .send()
             .await?
-            .json::<Vec<Track>>()
+            .error_for_status()?
+            .json::<Vec<Value>>()
             .await?;
         info!("Found {} tracks.", resp.len());
         Ok(resp)
     }
 }
 
+#[tokio::main]
+async fn get_playlists(server: &str) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+    let client = reqwest::Client::new();
+    unsafe {
+        let resp = client
+            .get(format!("{}/playlists", server).as_str())
+            .header("Authorization", format!("Basic {}", HTTP_AUTH))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<Value>>()
+            .await?;
+        info!("Found {} playlists.", resp.len());
+        Ok(resp)
+    }
+}
+
+/// CUE sheets of the library, each FILE with the index of the track it cuts.
+fn read_cue_sheets(server: &str, tracks: &[Track]) -> Vec<(usize, cue::Sheet)> {
+    let client = Client::new();
+    let mut sheets = Vec::new();
+    for (i, track) in tracks.iter().enumerate().filter(|(_, t)| cue::is_cue(t)) {
+        let path = track.path.as_ref().unwrap();
+        let parsed = http::fetch(&client, &format!("{}{}", server, path))
+            .map_err(|e| e.to_string())
+            .and_then(|data| cue::parse(&cue::decode(&data)));
+        match parsed {
+            Ok(parsed) => {
+                for sheet in parsed {
+                    match cue::audio_file(tracks, i, &sheet) {
+                        Some(file) => sheets.push((file, sheet)),
+                        None => warn!("No file {} of {} to split.", sheet.file, path),
+                    }
+                }
+            }
+            Err(e) => warn!("Can't read CUE sheet {}. {}", path, e),
+        }
+    }
+    info!("Found {} CUE sheets to split.", sheets.len());
+    sheets
+}
+
+/// Tunables coming from config file and command line.
+#[derive(Debug, Clone)]
+struct Options {
+    cache_max_count: u64,
+    cache_head: u64,
+    cache_tail: u64,
+    cache_block: u64,
+    cache_max_size: u64,
+    name_collision: Disambiguation,
+    trust_size: bool,
+    cache_dir: Option<PathBuf>,
+    cache_dir_max_size: u64,
+    pins: Vec<String>,
+    prefetch: Prefetch,
+    prefetch_at: f64,
+    download_connections: usize,
+    max_connections: usize,
+    search_ttl: u64,
+    path_template: Option<Template>,
+    safe_names: bool,
+    case_insensitive: bool,
+    scan_tags: bool,
+    rewrite_tags: bool,
+    covers: bool,
+    cover_endpoint: Option<String>,
+    cover_cache_size: u64,
+    lyrics: bool,
+    lyrics_endpoint: Option<String>,
+    cue_split: bool,
+}
+
+impl Default for Options {
+    /// Values used when neither the config file nor the command line sets them.
+    fn default() -> Options {
+        Options {
+            cache_max_count: 10,
+            cache_head: 768 * 1024,
+            cache_tail: 128 * 1024,
+            cache_block: 256 * 1024,
+            cache_max_size: 512 * 1024 * 1024,
+            name_collision: Disambiguation::Album,
+            trust_size: false,
+            cache_dir: None,
+            cache_dir_max_size: 10 * 1024 * 1024 * 1024,
+            pins: Vec::new(),
+            prefetch: Prefetch::Head,
+            prefetch_at: 0.5,
+            download_connections: 4,
+            max_connections: 8,
+            search_ttl: 60,
+            path_template: None,
+            safe_names: false,
+            case_insensitive: false,
+            scan_tags: false,
+            rewrite_tags: false,
+            covers: false,
+            cover_endpoint: None,
+            cover_cache_size: 100 * 1024 * 1024,
+            lyrics: false,
+            lyrics_endpoint: None,
+            cue_split: false,
+        }
+    }
+}
+
+/// Files generated on read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Virtual {
+    Service(Service),
+    // Index into `JsonFilesystem::playlists`.
+    Playlist(usize),
+}
+
+/// Service files in root.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Service {
+    Metrics,
+    Rejected,
+    Pinned,
+    Limits,
+}
+
+impl Service {
+    fn name(&self) -> &'static str {
+        match self {
+            Service::Metrics => "METRICS.TXT",
+            Service::Rejected => "REJECTED.TXT",
+            Service::Pinned => "PINNED.TXT",
+            Service::Limits => "LIMITS.TXT",
+        }
+    }
+}
+
+/// Files which exist only if there is something to put in them, looked for
+/// on open. Until then they are listed with no size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sidecar {
+    Cover,
+    Lyrics,
+}
+
+const SERVICE_FILES: [Service; 4] = [
+    Service::Metrics,
+    Service::Rejected,
+    Service::Pinned,
+    Service::Limits,
+];
+
+/// Block size reported by `statfs`.
+const FS_BLOCK: u64 = 4096;
+/// Directory with generated playlists.
+const PLAYLISTS_DIR: &str = "m3u";
+/// Directory with playlists from the server.
+const SERVER_PLAYLISTS_DIR: &str = "playlists";
+/// Directory where any looked up name becomes a search query.
+const SEARCH_DIR: &str = "search";
+
+/// Names of the album cover in every album directory.
+const COVER_NAMES: [&str; 2] = ["cover.jpg", "folder.jpg"];
+/// Names the cover takes once it turns out to be a PNG image.
+const PNG_COVER_NAMES: [&str; 2] = ["cover.png", "folder.png"];
+/// Longest tags read from the beginning of a file.
+const MAX_TAGS: u64 = 16 * 1024 * 1024;
+/// Bytes read in a row before a file counts as played, see `prefetch_next`.
+const PLAYBACK_RUN: u64 = 1024 * 1024;
+/// Cache blocks walked looking for a FLAC frame where a CUE track starts.
+const MAX_FRAME_WINDOWS: usize = 64;
+/// Timeouts of the streams reading files on, see `fetch_streamed`.
+const STREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const STREAM_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+const XATTR_PIN: &str = "user.mus.pin";
+const XATTR_PROGRESS: &str = "user.mus.progress";
+
+/// Name for an entry of root made by the filesystem itself, renamed if the
+/// library already has a track or directory of that name.
+fn root_name(root: &BTreeMap<String, u64>, name: &str) -> String {
+    let mut renamed = name.to_string();
+    let mut n = 1;
+    while root.contains_key(&renamed) {
+        let suffix = match n {
+            1 => "mus-fuse".to_string(),
+            n => format!("mus-fuse {}", n),
+        };
+        renamed = layout::with_suffix(name, &suffix);
+        n += 1;
+    }
+    if renamed != name {
+        warn!("The library has {} in root, it's {} here.", name, renamed);
+    }
+    renamed
+}
+
+/// With `allow_other` anyone can reach the mount, but only the user who
+/// mounted it (or root) may pin files and change limits.
+fn owns(req: &Request) -> bool {
+    let owner = unsafe { libc::getuid() };
+    req.uid() == owner || req.uid() == 0
+}
+
+/// Answer a getxattr/listxattr request, which first asks for the value size.
+/// Answer to getxattr and listxattr.
+#[derive(Debug, PartialEq)]
+enum Xattr {
+    Size(u32),
+    Data(Vec<u8>),
+}
+
+/// Size of `data` when asked with `size` 0, else `data` if it fits in `size`.
+fn xattr_reply(size: u32, data: Vec<u8>) -> Result<Xattr, c_int> {
+    if size == 0 {
+        Ok(Xattr::Size(data.len() as u32))
+    } else if data.len() > size as usize {
+        Err(ERANGE)
+    } else {
+        Ok(Xattr::Data(data))
+    }
+}
+
+fn reply_xattr(reply: ReplyXattr, xattr: Result<Xattr, c_int>) {
+    match xattr {
+        Ok(Xattr::Size(size)) => reply.size(size),
+        Ok(Xattr::Data(data)) => reply.data(&data),
+        Err(e) => reply.error(e),
+    }
+}
+
 #[cfg(target_family = "unix")]
 struct JsonFilesystem {
     server: String,
     tree: Vec<Track>,
     attrs: BTreeMap<u64, FileAttr>,
-    inodes: BTreeMap<String, u64>,
-    buffer_head_index: HashSet<u64>,
-    buffer_head_data: HashMap<u64, Vec<u8>>,
-    buffer_length: BTreeMap<String, i64>,
-    metrics_inode: u64,
-    cache_head: u64,
-    cache_max_count: u64,
+    // Directory inode -> entry name -> inode.
+    inodes: BTreeMap<u64, BTreeMap<String, u64>>,
+    // Album directory -> entries by disc and track number, listed in this order.
+    album_order: HashMap<u64, Vec<(String, u64)>>,
+    parents: HashMap<u64, u64>,
+    // Sum of track sizes from the library.
+    total_size: u64,
+    cache: Arc<Mutex<BlockCache>>,
+    buffer_length: BTreeMap<String, u64>,
+    virtual_files: HashMap<u64, Virtual>,
+    // Inode -> name it would have without `safe_names`.
+    original_names: HashMap<u64, String>,
+    // Path of every track inside the mount.
+    paths: Vec<String>,
+    // Depth of playlist file below root, and its tracks.
+    playlists: Vec<(usize, Selection)>,
+    // Playlist -> content, dropped when tags change.
+    rendered: HashMap<usize, Arc<String>>,
+    search_dir: u64,
+    // Directory -> normalized entry name -> inode, built on first inexact lookup
+    // and dropped whenever the directory changes.
+    lookup_keys: HashMap<u64, HashMap<String, u64>>,
+    // Query -> result directory and when it was made.
+    searches: HashMap<String, (u64, Instant)>,
+    // Query -> result directory ever made for it, so the inode stays the same
+    // for a shell sitting in it after the results expire.
+    search_inodes: HashMap<String, u64>,
+    next_inode: u64,
+    rejected: Vec<Rejected>,
+    store: Option<Arc<Mutex<Store>>>,
+    pinner: Option<Sender<pin::Job>>,
+    // Track inode -> next track of the same album.
+    next_track: HashMap<u64, u64>,
+    // Tracks whose next track was already prefetched since they were opened.
+    prefetched: HashSet<u64>,
+    // Open file handle -> end of its last read and bytes read in a row up to
+    // there, telling playback from tag readers.
+    playback: HashMap<u64, (u64, u64)>,
+    prefetcher: Option<Sender<prefetch::Job>>,
+    // Track inode and its length, as background downloads learn it.
+    length_sender: Sender<(u64, u64)>,
+    lengths: Receiver<(u64, u64)>,
+    // Shared by background downloads.
+    connections: Arc<Connections>,
+    // Open file handle -> its GET, if one is in progress.
+    handles: HashMap<u64, Option<Stream>>,
+    next_fh: u64,
+    stream_client: Option<Client>,
+    scanner: Option<Sender<scan::Job>>,
+    scan_results: Option<Receiver<(u64, Tags)>>,
+    // Tracks whose tags were scanned or queued for scanning.
+    scanned: HashSet<u64>,
+    // Track inode -> tag served in place of its own, see `rewrite_tags`.
+    splices: HashMap<u64, Splice>,
+    // Tracks found to keep their own tags with `rewrite_tags`.
+    unspliced: HashSet<u64>,
+    // Sidecar inode -> its kind and the track it comes from.
+    sidecars: HashMap<u64, (Sidecar, u64)>,
+    // Sidecar inode -> content, or `None` if there is none. Sidecars which
+    // couldn't be looked for yet aren't here.
+    sidecar_data: HashMap<u64, Option<Vec<u8>>>,
+    // Inode -> track of a CUE sheet it serves, cut on open into `splices`.
+    cue_parts: HashMap<u64, cue::Part>,
+    options: Options,
 }
 
 #[cfg(target_family = "unix")]
 impl JsonFilesystem {
     fn new(
-        tree: &Vec<Track>,
+        tree: &[Track],
+        rejected: Vec<Rejected>,
+        server_playlists: &[ServerPlaylist],
+        cue_sheets: &[(usize, cue::Sheet)],
         server: String,
-        cache_max_count: u64,
-        cache_head: u64,
+        options: Options,
     ) -> JsonFilesystem {
         let mut attrs = BTreeMap::new();
-        let mut inodes = BTreeMap::new();
+        let mut inodes: BTreeMap<u64, BTreeMap<String, u64>> = BTreeMap::new();
+        let mut parents = HashMap::new();
         let ts = time::now().to_timespec();
         let mut total_size: i64 = 0;
-        let attr = FileAttr {
-            ino: 1,
+        let dir_attr = |ino: u64| FileAttr {
+            ino,
             size: 0,
             blocks: 0,
             atime: ts,
@@ -157,23 +536,55 @@ impl JsonFilesystem {
             rdev: 0,
             flags: 0,
         };
-        attrs.insert(1, attr);
-        inodes.insert("/".to_string(), 1);
-        for (i, track) in tree.iter().enumerate() {
-            let basename = get_basename(track.path.as_ref()).unwrap().to_string();
+        attrs.insert(1, dir_attr(1));
+        inodes.insert(1, BTreeMap::new());
+        parents.insert(1, 1);
+        // Directories and playlists get inodes after tracks and the service files.
+        let mut next_inode = 2 + (tree.len() + SERVICE_FILES.len()) as u64;
+        // Directory -> album of all tracks in it, None if they differ.
+        let mut dir_albums: HashMap<u64, Option<&str>> = HashMap::new();
+        let layout = layout::layout(
+            tree,
+            options.name_collision,
+            options.path_template.as_ref(),
+            options.safe_names,
+        );
+        if layout.collisions > 0 {
+            warn!(
+                "{} tracks have clashing names, renamed using '{}' strategy.",
+                layout.collisions, options.name_collision
+            );
+        }
+        METRICS.lock().unwrap().name_collisions = layout.collisions;
+        let mut original_names = HashMap::new();
+        let safe = |name: String| {
+            if options.safe_names {
+                text::safe_name(&name)
+            } else {
+                name
+            }
+        };
+        let next_track = layout::next_in_album(tree, &layout.paths)
+            .into_iter()
+            .map(|(track, next)| (track as u64 + 2, next as u64 + 2))
+            .collect();
+        for (i, (track, path)) in tree.iter().zip(layout.paths.iter()).enumerate() {
+            let (basename, dirs) = path.split_last().unwrap();
             debug!(
                 "Added inode: {} - {} [{}]",
                 i + 2,
-                basename,
+                path.join("/"),
                 track.size.unwrap()
             );
-            total_size = total_size + track.size.unwrap();
+            total_size = total_size.saturating_add(track.size.unwrap());
             let attr = FileAttr {
                 ino: i as u64 + 2,
                 size: track.size.unwrap() as u64,
                 blocks: 0,
                 atime: ts,
-                mtime: ts,
+                mtime: track
+                    .modified
+                    .map_or(ts, |modified| Timespec::new(modified, 0)),
                 ctime: ts,
                 crtime: ts,
                 kind: FileType::RegularFile,
@@ -184,76 +595,1735 @@ impl JsonFilesystem {
                 rdev: 0,
                 flags: 0,
             };
+            let mut parent = 1;
+            for (n, dir) in dirs.iter().enumerate() {
+                parent = match inodes[&parent].get(dir) {
+                    Some(&ino) => ino,
+                    None => {
+                        let ino = next_inode;
+                        next_inode += 1;
+                        if let Some(original) = layout.originals.get(&path[..=n]) {
+                            original_names.insert(ino, original.clone());
+                        }
+                        attrs.insert(ino, dir_attr(ino));
+                        inodes.insert(ino, BTreeMap::new());
+                        inodes.get_mut(&parent).unwrap().insert(dir.clone(), ino);
+                        parents.insert(ino, parent);
+                        ino
+                    }
+                };
+            }
+            if let Some(original) = layout.originals.get(path.as_slice()) {
+                original_names.insert(attr.ino, original.clone());
+            }
             attrs.insert(attr.ino, attr);
-            inodes.insert(basename.clone(), attr.ino);
+            inodes
+                .get_mut(&parent)
+                .unwrap()
+                .insert(basename.clone(), attr.ino);
+            parents.insert(attr.ino, parent);
+            if parent != 1 {
+                let album = dir_albums
+                    .entry(parent)
+                    .or_insert_with(|| track.album.as_deref());
+                if *album != track.album.as_deref() {
+                    *album = None;
+                }
+            }
         }
-        // Metrics file
-        let metrics_inode = 2 + tree.len() as u64;
-        let metrics_attr = FileAttr {
-            ino: metrics_inode,
-            size: 4096,
+        // Service files
+        let virtual_attr = |ino: u64, perm: u16| FileAttr {
+            ino,
+            size: 0,
             blocks: 0,
             atime: ts,
             mtime: ts,
             ctime: ts,
             crtime: ts,
             kind: FileType::RegularFile,
-            perm: 0o444,
+            perm,
             nlink: 0,
             uid: 0,
             gid: 0,
             rdev: 0,
             flags: 0,
         };
-        attrs.insert(metrics_attr.ino, metrics_attr);
-        inodes.insert("METRICS.TXT".to_string(), metrics_attr.ino);
+        let mut virtual_files = HashMap::new();
+        for (i, file) in SERVICE_FILES.iter().enumerate() {
+            // Limits are changed by writing to the file.
+            let perm = if *file == Service::Limits {
+                0o644
+            } else {
+                0o444
+            };
+            let attr = virtual_attr(2 + (tree.len() + i) as u64, perm);
+            attrs.insert(attr.ino, attr);
+            let root = inodes.get_mut(&1).unwrap();
+            root.insert(root_name(root, file.name()), attr.ino);
+            parents.insert(attr.ino, 1);
+            virtual_files.insert(attr.ino, Virtual::Service(*file));
+        }
+        // Album covers, one file under both names.
+        let mut sidecars = HashMap::new();
+        if options.covers {
+            let mut albums: BTreeMap<u64, usize> = BTreeMap::new();
+            let order = |i: usize| (tree[i].disc_number, tree[i].track_number, &layout.paths[i]);
+            for i in 0..tree.len() {
+                let dir = parents[&(i as u64 + 2)];
+                if dir == 1 {
+                    continue;
+                }
+                let first = albums.entry(dir).or_insert(i);
+                if order(i) < order(*first) {
+                    *first = i;
+                }
+            }
+            for (dir, first) in albums {
+                let entries = inodes.get_mut(&dir).unwrap();
+                if COVER_NAMES.iter().any(|name| entries.contains_key(*name)) {
+                    continue;
+                }
+                let attr = virtual_attr(next_inode, 0o444);
+                next_inode += 1;
+                for name in COVER_NAMES.iter() {
+                    entries.insert(name.to_string(), attr.ino);
+                }
+                attrs.insert(attr.ino, attr);
+                parents.insert(attr.ino, dir);
+                sidecars.insert(attr.ino, (Sidecar::Cover, first as u64 + 2));
+            }
+        }
+        // Lyrics next to tracks, named after them.
+        if options.lyrics {
+            for (i, path) in layout.paths.iter().enumerate() {
+                let track = i as u64 + 2;
+                let dir = parents[&track];
+                let basename = path.last().unwrap();
+                let stem = match basename.rfind('.') {
+                    Some(dot) if dot > 0 => &basename[..dot],
+                    _ => basename.as_str(),
+                };
+                let name = text::truncate_name(&format!("{}.lrc", stem));
+                let entries = inodes.get_mut(&dir).unwrap();
+                if entries.contains_key(&name) {
+                    continue;
+                }
+                let attr = virtual_attr(next_inode, 0o444);
+                next_inode += 1;
+                entries.insert(name, attr.ino);
+                attrs.insert(attr.ino, attr);
+                parents.insert(attr.ino, dir);
+                sidecars.insert(attr.ino, (Sidecar::Lyrics, track));
+            }
+        }
+        // Tracks of CUE sheets, in a directory named after the file they
+        // are cut from.
+        let mut cue_parts = HashMap::new();
+        if options.cue_split {
+            for (file, sheet) in cue_sheets {
+                let source = *file as u64 + 2;
+                let dir = parents[&source];
+                let basename = layout.paths[*file].last().unwrap();
+                let ext = cue::extension(basename);
+                let name = basename[..basename.len() - ext.len()].trim_end_matches('.');
+                if name.is_empty() || inodes[&dir].contains_key(name) {
+                    warn!("No room for tracks of {} next to it.", basename);
+                    continue;
+                }
+                let ino = next_inode;
+                next_inode += 1;
+                attrs.insert(ino, dir_attr(ino));
+                parents.insert(ino, dir);
+                inodes.get_mut(&dir).unwrap().insert(name.to_string(), ino);
+                let size = attrs[&source].size;
+                let whole = tree[*file].duration;
+                let mut entries = BTreeMap::new();
+                let tracks = cue::tracks(sheet, &tree[*file]);
+                for (i, (part, track)) in sheet.tracks.iter().zip(tracks).enumerate() {
+                    // Real size is known once the part is cut on open.
+                    let estimate = match (track.duration, whole) {
+                        (Some(duration), Some(whole)) if whole > 0.0 => {
+                            (size as f64 * (duration / whole).min(1.0)) as u64
+                        }
+                        _ => size / sheet.tracks.len() as u64,
+                    };
+                    let title = match &track.name {
+                        Some(title) => template::sanitize(title),
+                        None => format!("Track {:02}", part.number),
+                    };
+                    let name = format!("{:02} - {}.{}", part.number, title, ext);
+                    let attr = FileAttr {
+                        size: estimate,
+                        ..virtual_attr(next_inode, 0o444)
+                    };
+                    next_inode += 1;
+                    entries.insert(text::truncate_name(&safe(name)), attr.ino);
+                    attrs.insert(attr.ino, attr);
+                    parents.insert(attr.ino, ino);
+                    cue_parts.insert(
+                        attr.ino,
+                        cue::Part {
+                            source,
+                            track,
+                            start: part.start,
+                            end: sheet.tracks.get(i + 1).map(|next| next.start),
+                        },
+                    );
+                }
+                inodes.insert(ino, entries);
+            }
+        }
+        // Playlists, relative paths in them lead back to root.
+        let mut playlists = Vec::new();
+        let playlists_dir = next_inode;
+        next_inode += 1;
+        attrs.insert(playlists_dir, dir_attr(playlists_dir));
+        inodes.insert(playlists_dir, BTreeMap::new());
+        let root = inodes.get_mut(&1).unwrap();
+        root.insert(root_name(root, PLAYLISTS_DIR), playlists_dir);
+        parents.insert(playlists_dir, 1);
+        for (dir, name, selection) in playlist::playlists(tree) {
+            let (parent, depth) = match dir {
+                Some(dir) => match inodes[&playlists_dir].get(dir) {
+                    Some(&ino) => (ino, 2),
+                    None => {
+                        let ino = next_inode;
+                        next_inode += 1;
+                        attrs.insert(ino, dir_attr(ino));
+                        inodes.insert(ino, BTreeMap::new());
+                        inodes
+                            .get_mut(&playlists_dir)
+                            .unwrap()
+                            .insert(dir.to_string(), ino);
+                        parents.insert(ino, playlists_dir);
+                        (ino, 2)
+                    }
+                },
+                None => (playlists_dir, 1),
+            };
+            let attr = virtual_attr(next_inode, 0o444);
+            next_inode += 1;
+            attrs.insert(attr.ino, attr);
+            inodes
+                .get_mut(&parent)
+                .unwrap()
+                .insert(safe(name), attr.ino);
+            parents.insert(attr.ino, parent);
+            virtual_files.insert(attr.ino, Virtual::Playlist(playlists.len()));
+            playlists.push((depth, selection));
+        }
+        // Server playlists: a directory of numbered links to tracks and an
+        // M3U next to it.
+        let server_playlists = playlist::resolve_server_playlists(server_playlists, tree);
+        if !server_playlists.is_empty() {
+            let dir = next_inode;
+            next_inode += 1;
+            attrs.insert(dir, dir_attr(dir));
+            inodes.insert(dir, BTreeMap::new());
+            let root = inodes.get_mut(&1).unwrap();
+            root.insert(root_name(root, SERVER_PLAYLISTS_DIR), dir);
+            parents.insert(dir, 1);
+            for (name, listed) in server_playlists {
+                let ino = next_inode;
+                next_inode += 1;
+                attrs.insert(ino, dir_attr(ino));
+                parents.insert(ino, dir);
+                let width = listed.len().to_string().len().max(2);
+                let entries = listed
+                    .iter()
+                    .enumerate()
+                    .map(|(n, &i)| {
+                        let basename = layout.paths[i].last().unwrap();
+                        // Same inode as the track itself, its parent stays.
+                        let name = format!("{:0width$} {}", n + 1, basename);
+                        (safe(name), i as u64 + 2)
+                    })
+                    .collect();
+                inodes.insert(ino, entries);
+                let attr = virtual_attr(next_inode, 0o444);
+                next_inode += 1;
+                attrs.insert(attr.ino, attr);
+                let parent = inodes.get_mut(&dir).unwrap();
+                parent.insert(safe(name.clone()), ino);
+                parent.insert(safe(format!("{}.m3u8", name)), attr.ino);
+                parents.insert(attr.ino, dir);
+                virtual_files.insert(attr.ino, Virtual::Playlist(playlists.len()));
+                playlists.push((1, Selection::Listed(listed)));
+            }
+        }
+        // Results of searches get added on lookup.
+        let search_dir = next_inode;
+        next_inode += 1;
+        attrs.insert(search_dir, dir_attr(search_dir));
+        inodes.insert(search_dir, BTreeMap::new());
+        let root = inodes.get_mut(&1).unwrap();
+        root.insert(root_name(root, SEARCH_DIR), search_dir);
+        parents.insert(search_dir, 1);
+        // Tracks of an album go by disc and track number, other entries first.
+        let mut album_order = HashMap::new();
+        for (dir, album) in dir_albums {
+            if album.is_none() {
+                continue;
+            }
+            let mut entries: Vec<(String, u64)> = inodes[&dir]
+                .iter()
+                .map(|(name, &ino)| (name.clone(), ino))
+                .collect();
+            entries.sort_by_key(
+                |(name, ino)| match tree.get((*ino as usize).wrapping_sub(2)) {
+                    Some(track) => (Some((track.disc_number, track.track_number)), name.clone()),
+                    None => (None, name.clone()),
+                },
+            );
+            album_order.insert(dir, entries);
+        }
+        METRICS.lock().unwrap().rejected_tracks = rejected.len() as u64;
         info!(
             "Filesystem initialized. Size: {} files, {}B in total.",
-            inodes.len(),
+            tree.len(),
             (SizeFormatterBinary::new(total_size as u64))
         );
+        let (length_sender, lengths) = channel();
         JsonFilesystem {
-            server: server,
-            tree: tree.clone(),
-            attrs: attrs,
-            inodes: inodes,
-            buffer_head_data: HashMap::new(),
-            buffer_head_index: HashSet::new(),
+            server,
+            tree: tree.to_vec(),
+            total_size: total_size as u64,
+            attrs,
+            inodes,
+            album_order,
+            parents,
+            cache: Arc::new(Mutex::new(BlockCache::new(
+                options.cache_block,
+                options.cache_max_count as usize,
+                options.cache_max_size,
+            ))),
             buffer_length: BTreeMap::new(),
-            metrics_inode: metrics_inode,
-            cache_head: cache_head,
-            cache_max_count: cache_max_count,
+            virtual_files,
+            original_names,
+            paths: layout.paths.iter().map(|path| path.join("/")).collect(),
+            playlists,
+            rendered: HashMap::new(),
+            search_dir,
+            lookup_keys: HashMap::new(),
+            searches: HashMap::new(),
+            search_inodes: HashMap::new(),
+            next_inode,
+            rejected,
+            store: None,
+            pinner: None,
+            next_track,
+            prefetched: HashSet::new(),
+            playback: HashMap::new(),
+            prefetcher: None,
+            length_sender,
+            lengths,
+            connections: Arc::new(Connections::new(
+                options.download_connections,
+                options.max_connections,
+            )),
+            handles: HashMap::new(),
+            next_fh: 1,
+            stream_client: None,
+            scanner: None,
+            scan_results: None,
+            scanned: HashSet::new(),
+            splices: HashMap::new(),
+            unspliced: HashSet::new(),
+            sidecars,
+            sidecar_data: HashMap::new(),
+            cue_parts,
+            options,
+        }
+    }
+
+    /// Open the disk cache and start downloading pinned tracks.
+    fn start_pinning(&mut self) {
+        let dir = match &self.options.cache_dir {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        let store = match Store::open(
+            &dir,
+            self.options.cache_dir_max_size,
+            self.options.cover_cache_size,
+        ) {
+            Ok(store) => Arc::new(Mutex::new(store)),
+            Err(e) => {
+                error!("Can't open disk cache {}. {}", dir.display(), e);
+                return;
+            }
+        };
+        info!("Disk cache at {}", dir.display());
+        self.pinner = Some(pin::spawn(
+            store.clone(),
+            self.connections.clone(),
+            self.length_sender.clone(),
+        ));
+        self.store = Some(store);
+        for spec in self.options.pins.clone() {
+            let tracks = self.resolve(&spec);
+            if tracks.is_empty() {
+                warn!("Pin {} matches no tracks.", spec);
+            }
+            for ino in tracks {
+                let id = self.track(ino).id.clone().unwrap();
+                self.store
+                    .as_ref()
+                    .unwrap()
+                    .lock()
+                    .unwrap()
+                    .pin_from_config(&id);
+            }
+        }
+        // Resume pins of previous runs along with the config ones.
+        for ino in 2..2 + self.tree.len() as u64 {
+            let id = self.track(ino).id.clone().unwrap();
+            let store = self.store.as_ref().unwrap().lock().unwrap();
+            if store.is_pinned(&id) && !store.is_complete(&id) {
+                drop(store);
+                self.queue_download(ino);
+            }
+        }
+    }
+
+    /// Start fetching tracks ahead of the player.
+    fn start_prefetch(&mut self) {
+        if self.options.prefetch != Prefetch::Off {
+            self.prefetcher = Some(prefetch::spawn(
+                self.cache.clone(),
+                self.connections.clone(),
+                self.length_sender.clone(),
+            ));
+        }
+    }
+
+    /// Start scanning tags of tracks as their heads get cached. Tracks in
+    /// `known` were scanned by previous runs.
+    fn start_scanning(&mut self, known: HashMap<String, Tags>) {
+        if !self.options.scan_tags {
+            return;
+        }
+        for ino in 2..2 + self.tree.len() as u64 {
+            if known.contains_key(self.track(ino).id.as_ref().unwrap()) {
+                self.scanned.insert(ino);
+            }
+        }
+        let (scanner, results) = scan::spawn(self.options.cache_dir.clone(), known);
+        self.scanner = Some(scanner);
+        self.scan_results = Some(results);
+    }
+
+    /// Queue the head of `ino` for tag scanning, if it is cached.
+    fn queue_scan(&mut self, ino: u64, length: u64) {
+        let scanner = match &self.scanner {
+            Some(scanner) if !self.scanned.contains(&ino) => scanner,
+            _ => return,
+        };
+        let head = match range::head_range(length, self.options.cache_head) {
+            Some(head) => head,
+            None => return,
+        };
+        let id = self.track(ino).id.clone().unwrap();
+        let stored = match &self.store {
+            Some(store) => {
+                let mut store = store.lock().unwrap();
+                if store.is_complete(&id) {
+                    store.read(&id, head).ok()
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+        let head = match stored {
+            Some(data) => data,
+            None => {
+                let mut cache = self.cache.lock().unwrap();
+                if !cache.missing(ino, length, head).is_empty() {
+                    return;
+                }
+                cache.read(ino, head)
+            }
+        };
+        self.scanned.insert(ino);
+        scanner.send(scan::Job { ino, id, head }).unwrap();
+    }
+
+    /// Fill metadata the library lacks with tags scanned so far. Paths are
+    /// kept until the next mount.
+    fn merge_tags(&mut self) {
+        let results = match &self.scan_results {
+            Some(results) => results,
+            None => return,
+        };
+        let mut merged = false;
+        for (ino, tags) in results.try_iter() {
+            tags.fill(&mut self.tree[(ino - 2) as usize]);
+            merged = true;
+        }
+        if merged {
+            self.rendered.clear();
+        }
+    }
+
+    /// Take lengths learned by background downloads into account, so reads
+    /// go up to them and blocks cached under them are found.
+    fn merge_lengths(&mut self) {
+        let learned: Vec<(u64, u64)> = self.lengths.try_iter().collect();
+        for (ino, length) in learned {
+            self.check_length(ino, Some(length));
+        }
+    }
+
+    /// Queue the track following `ino` on its album, once `ino` is mostly played.
+    fn prefetch_next(&mut self, ino: u64, fh: u64, range: Range, length: u64) {
+        let run = match self.playback.get(&fh) {
+            Some(&(end, run)) if end == range.start => run + range.len(),
+            _ => range.len(),
+        };
+        self.playback.insert(fh, (range.end, run));
+        let prefetcher = match &self.prefetcher {
+            Some(prefetcher) => prefetcher,
+            None => return,
+        };
+        // Tag readers jump to the tail, players read on and on.
+        let playing = run >= PLAYBACK_RUN.min(length / 4);
+        if self.prefetched.contains(&ino)
+            || !playing
+            || (range.end as f64) < self.options.prefetch_at * length as f64
+        {
+            return;
+        }
+        self.prefetched.insert(ino);
+        let next = match self.next_track.get(&ino) {
+            Some(&next) => next,
+            None => return,
+        };
+        let track = self.track(next);
+        let id = track.id.as_ref().unwrap();
+        if let Some(store) = &self.store {
+            if store.lock().unwrap().is_complete(id) {
+                return;
+            }
+        }
+        let length = match self.buffer_length.get(id) {
+            Some(&length) => length,
+            None => self.attrs[&next].size,
+        };
+        let range = match self.options.prefetch {
+            Prefetch::Full => range::head_range(length, length),
+            _ => range::head_range(length, self.options.cache_head),
+        };
+        if let Some(range) = range {
+            debug!("{} - Prefetching next track {}", ino, next);
+            METRICS.lock().unwrap().prefetches += 1;
+            let job = prefetch::Job {
+                ino: next,
+                url: format!("{}{}", self.server, track.path.as_ref().unwrap()),
+                length,
+                range,
+            };
+            prefetcher.send(job).unwrap();
+        }
+    }
+
+    /// Result directory of a search, made anew once the old one expired.
+    fn search(&mut self, query: &str) -> u64 {
+        let ttl = Duration::from_secs(self.options.search_ttl);
+        let expired: Vec<String> = self
+            .searches
+            .iter()
+            .filter(|(_, (_, made))| made.elapsed() >= ttl)
+            .map(|(query, _)| query.clone())
+            .collect();
+        // Expired results stay readable until the query is searched again.
+        for query in expired {
+            let (ino, _) = self.searches.remove(&query).unwrap();
+            self.inodes
+                .get_mut(&self.search_dir)
+                .unwrap()
+                .remove(&query);
+            self.lookup_keys.remove(&self.search_dir);
+            self.lookup_keys.remove(&ino);
+        }
+        if let Some(&(ino, _)) = self.searches.get(query) {
+            return ino;
+        }
+        let words = search::words(query);
+        let mut entries = BTreeMap::new();
+        for (i, track) in self.tree.iter().enumerate() {
+            if !search::matches(&words, track) {
+                continue;
+            }
+            let basename = self.paths[i].rsplit('/').next().unwrap();
+            let name = if entries.contains_key(basename) {
+                layout::with_suffix(basename, track.id.as_ref().unwrap())
+            } else {
+                basename.to_string()
+            };
+            entries.insert(name, i as u64 + 2);
+        }
+        info!("Search '{}' found {} tracks", query, entries.len());
+        let ino = match self.search_inodes.get(query) {
+            Some(&ino) => ino,
+            None => {
+                let ino = self.next_inode;
+                self.next_inode += 1;
+                self.search_inodes.insert(query.to_string(), ino);
+                ino
+            }
+        };
+        self.attrs.insert(
+            ino,
+            FileAttr {
+                ino,
+                ..self.attrs[&1]
+            },
+        );
+        self.inodes.insert(ino, entries);
+        self.lookup_keys.remove(&ino);
+        self.lookup_keys.remove(&self.search_dir);
+        self.parents.insert(ino, self.search_dir);
+        self.inodes
+            .get_mut(&self.search_dir)
+            .unwrap()
+            .insert(query.to_string(), ino);
+        self.searches
+            .insert(query.to_string(), (ino, Instant::now()));
+        ino
+    }
+
+    /// Form of a name used to match lookups: NFC, lowercase if lookups are
+    /// case insensitive.
+    fn lookup_key(&self, name: &str) -> String {
+        let name = text::nfc(name);
+        if self.options.case_insensitive {
+            name.to_lowercase()
+        } else {
+            name
+        }
+    }
+
+    /// Entry of `parent` called `name`. Names also match in another Unicode
+    /// normalization form, as far as `text::nfc` goes, in other case with `case_insensitive`, and as they
+    /// were before `safe_names` replaced them.
+    fn find_entry(&mut self, parent: u64, name: &str) -> Option<u64> {
+        let entries = self.inodes.get(&parent)?;
+        if let Some(&ino) = entries.get(name) {
+            return Some(ino);
+        }
+        if !self.lookup_keys.contains_key(&parent) {
+            let mut keys = HashMap::new();
+            for (entry, &ino) in entries {
+                keys.entry(self.lookup_key(entry)).or_insert(ino);
+            }
+            self.lookup_keys.insert(parent, keys);
+        }
+        let keys = &self.lookup_keys[&parent];
+        let mut ino = keys.get(&self.lookup_key(name)).copied();
+        if ino.is_none() && self.options.safe_names {
+            ino = keys.get(&self.lookup_key(&text::safe_name(name))).copied();
+        }
+        ino
+    }
+
+    /// Attributes as shown to the kernel, sized for the rewritten tag or
+    /// the sidecar content.
+    fn shown_attr(&self, ino: u64, attr: &FileAttr) -> FileAttr {
+        // Tracks of CUE sheets are cut from the whole file.
+        let length = match self.cue_parts.get(&ino) {
+            Some(part) => self.attrs[&part.source].size,
+            None => attr.size,
+        };
+        if let Some(Some(data)) = self.sidecar_data.get(&ino) {
+            return FileAttr {
+                size: data.len() as u64,
+                ..*attr
+            };
+        }
+        match self.splices.get(&ino) {
+            Some(splice) => FileAttr {
+                size: splice.size(length),
+                ..*attr
+            },
+            None => *attr,
+        }
+    }
+
+    /// Whether the size of `ino` is only known once it's opened, because its
+    /// tags get rewritten, it's cut from a CUE sheet file or it's a sidecar
+    /// not looked for yet.
+    fn size_pending(&self, ino: u64) -> bool {
+        if self.sidecars.contains_key(&ino) {
+            return !self.sidecar_data.contains_key(&ino);
+        }
+        if self.cue_parts.contains_key(&ino) {
+            return !self.splices.contains_key(&ino);
+        }
+        self.options.rewrite_tags
+            && self.is_track(ino)
+            && !self.splices.contains_key(&ino)
+            && !self.unspliced.contains(&ino)
+    }
+
+    /// How long the kernel may keep attributes of `ino`. Sizes which are
+    /// still to change on open aren't kept at all.
+    fn attr_ttl(&self, ino: u64) -> Timespec {
+        if self.size_pending(ino) {
+            Timespec::new(0, 0)
+        } else {
+            Timespec::new(1, 0)
+        }
+    }
+
+    fn is_track(&self, ino: u64) -> bool {
+        ino >= 2 && ino < 2 + self.tree.len() as u64
+    }
+
+    fn track(&self, ino: u64) -> &Track {
+        &self.tree[(ino - 2) as usize]
+    }
+
+    /// All tracks at or below `ino`.
+    fn tracks_under(&self, ino: u64) -> Vec<u64> {
+        if self.is_track(ino) {
+            return vec![ino];
+        }
+        match self.inodes.get(&ino) {
+            Some(entries) => entries
+                .values()
+                .flat_map(|&child| self.tracks_under(child))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Path of an inode inside the mount.
+    fn path_of(&self, ino: u64) -> String {
+        let mut names = Vec::new();
+        let mut ino = ino;
+        while ino != 1 {
+            let parent = self.parents[&ino];
+            if let Some((name, _)) = self.inodes[&parent].iter().find(|(_, &x)| x == ino) {
+                names.push(name.as_str());
+            }
+            ino = parent;
+        }
+        names.reverse();
+        names.join("/")
+    }
+
+    /// Tracks matching a pin from config: `artist:Name`, `album:Name`, or a
+    /// file or directory path inside the mount.
+    fn resolve(&self, spec: &str) -> Vec<u64> {
+        let by_field = |field: fn(&Track) -> Option<&String>, value: &str| -> Vec<u64> {
+            (2..2 + self.tree.len() as u64)
+                .filter(|&ino| field(self.track(ino)).map(|x| x.as_str()) == Some(value))
+                .collect()
+        };
+        if let Some(artist) = spec.strip_prefix("artist:") {
+            return by_field(|track| track.artist.as_ref(), artist);
+        }
+        if let Some(album) = spec.strip_prefix("album:") {
+            return by_field(|track| track.album.as_ref(), album);
+        }
+        let mut ino = 1;
+        for name in spec.split('/').filter(|name| !name.is_empty()) {
+            ino = match self.inodes.get(&ino).and_then(|entries| entries.get(name)) {
+                Some(&child) => child,
+                None => return Vec::new(),
+            };
+        }
+        self.tracks_under(ino)
+    }
+
+    fn queue_download(&self, ino: u64) {
+        let track = self.track(ino);
+        let job = pin::Job {
+            ino,
+            id: track.id.clone().unwrap(),
+            url: format!("{}{}", self.server, track.path.as_ref().unwrap()),
+            length: self.attrs[&ino].size,
+        };
+        if let Some(pinner) = &self.pinner {
+            pinner.send(job).unwrap();
+        }
+    }
+
+    /// Pin or unpin every track at or below `ino`.
+    fn set_pinned(&mut self, ino: u64, pinned: bool) -> bool {
+        let store = match &self.store {
+            Some(store) => store.clone(),
+            None => {
+                warn!("Pinning needs cache_dir to be set.");
+                return false;
+            }
+        };
+        for track in self.tracks_under(ino) {
+            let id = self.track(track).id.clone().unwrap();
+            if pinned {
+                let new = store.lock().unwrap().pin(&id);
+                if new {
+                    info!("Pinned {}", self.path_of(track));
+                    self.queue_download(track);
+                }
+            } else {
+                store.lock().unwrap().unpin(&id);
+            }
+        }
+        true
+    }
+
+    /// Pinned state and download progress of tracks at or below `ino`.
+    fn pin_state(&self, ino: u64) -> Option<(bool, store::Progress)> {
+        let store = self.store.as_ref()?.lock().unwrap();
+        let tracks = self.tracks_under(ino);
+        if tracks.is_empty() {
+            return None;
+        }
+        let mut pinned = true;
+        let mut total = store::Progress {
+            complete: true,
+            ..Default::default()
+        };
+        for track in tracks {
+            let id = self.track(track).id.as_ref().unwrap();
+            let progress = store.progress(id);
+            pinned &= store.is_pinned(id);
+            total.downloaded += progress.downloaded.min(self.attrs[&track].size);
+            total.length += self.attrs[&track].size;
+            total.complete &= progress.complete;
+        }
+        Some((pinned, total))
+    }
+
+    /// Extended attributes of an inode: pin state of tracks and directories,
+    /// library metadata and cache state of tracks.
+    fn xattrs(&self, ino: u64) -> Vec<(&'static str, String)> {
+        let mut xattrs = Vec::new();
+        if let Some((pinned, progress)) = self.pin_state(ino) {
+            xattrs.push((XATTR_PIN, if pinned { "1" } else { "0" }.to_string()));
+            xattrs.push((
+                XATTR_PROGRESS,
+                format!("{}/{}", progress.downloaded, progress.length),
+            ));
+        }
+        if let Some(original) = self.original_names.get(&ino) {
+            xattrs.push(("user.mus.original_name", original.clone()));
+        }
+        if !self.is_track(ino) {
+            return xattrs;
+        }
+        let track = self.track(ino);
+        let fields = [
+            ("user.mus.id", track.id.clone()),
+            ("user.mus.artist", track.artist.clone()),
+            ("user.mus.album", track.album.clone()),
+            ("user.mus.genre", track.genre.clone()),
+            ("user.mus.year", track.year.map(|year| year.to_string())),
+            ("user.mus.format", track.format.clone()),
+            ("user.mus.album_artist", track.album_artist.clone()),
+            (
+                "user.mus.track",
+                track.track_number.map(|number| number.to_string()),
+            ),
+            (
+                "user.mus.disc",
+                track.disc_number.map(|number| number.to_string()),
+            ),
+            (
+                "user.mus.duration",
+                track.duration.map(|duration| duration.to_string()),
+            ),
+            (
+                "user.mus.bitrate",
+                track.bitrate.map(|bitrate| bitrate.to_string()),
+            ),
+            (
+                "user.mus.url",
+                Some(format!("{}{}", self.server, track.path.as_ref().unwrap())),
+            ),
+        ];
+        for (name, value) in fields.iter() {
+            if let Some(value) = value {
+                xattrs.push((name, value.clone()));
+            }
+        }
+        let mut cached = self.cache.lock().unwrap().cached_bytes(ino);
+        if let Some(store) = &self.store {
+            let progress = store.lock().unwrap().progress(track.id.as_ref().unwrap());
+            cached = cached.max(progress.downloaded);
+        }
+        xattrs.push(("user.mus.cached_bytes", cached.to_string()));
+        xattrs
+    }
+
+    /// Blocks, free blocks, files and free files reported by `statfs`. The
+    /// library is the size of the filesystem, what still fits into the disk
+    /// cache and the tracks not kept there yet are free. Without the disk
+    /// cache nothing can be kept, so nothing is free.
+    fn fs_stats(&self) -> (u64, u64, u64, u64) {
+        let blocks = self.total_size.div_ceil(FS_BLOCK);
+        let files = self.tree.len() as u64;
+        let store = match &self.store {
+            Some(store) => store.lock().unwrap(),
+            None => return (blocks, 0, files, 0),
+        };
+        let free = (store.free() / FS_BLOCK).min(blocks);
+        let stored = self
+            .tree
+            .iter()
+            .filter(|track| store.is_complete(track.id.as_ref().unwrap()))
+            .count() as u64;
+        (blocks, free, files, files - stored)
+    }
+
+    /// Value of xattr `name`, or its size when asked with `size` 0.
+    fn get_xattr(&mut self, ino: u64, name: &OsStr, size: u32) -> Result<Xattr, c_int> {
+        self.merge_tags();
+        match self
+            .xattrs(ino)
+            .into_iter()
+            .find(|(xattr, _)| name == *xattr)
+        {
+            Some((_, value)) => xattr_reply(size, value.into_bytes()),
+            None => Err(ENODATA),
+        }
+    }
+
+    /// Names of the xattrs of `ino`, each ending with a NUL.
+    fn list_xattr(&self, ino: u64, size: u32) -> Result<Xattr, c_int> {
+        let mut names = Vec::new();
+        for (name, _) in self.xattrs(ino) {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        xattr_reply(size, names)
+    }
+
+    /// Pin or unpin tracks at or below `ino`, the only xattr which can be set.
+    fn set_xattr(&mut self, ino: u64, name: &OsStr, value: &[u8]) -> Result<(), c_int> {
+        if name != XATTR_PIN {
+            return Err(ENOTSUP);
+        }
+        let pinned = match String::from_utf8_lossy(value).trim() {
+            "1" | "true" | "yes" => true,
+            "0" | "false" | "no" => false,
+            _ => return Err(EINVAL),
+        };
+        if self.set_pinned(ino, pinned) {
+            Ok(())
+        } else {
+            Err(ENOTSUP)
+        }
+    }
+
+    /// Content of a virtual file. Playlists are rendered once, service files
+    /// every time.
+    fn content(&mut self, file: Virtual) -> Arc<String> {
+        let i = match file {
+            Virtual::Playlist(i) => i,
+            Virtual::Service(_) => return Arc::new(self.render(file)),
+        };
+        if let Some(content) = self.rendered.get(&i) {
+            return content.clone();
+        }
+        let content = Arc::new(self.render(file));
+        self.rendered.insert(i, content.clone());
+        content
+    }
+
+    fn render(&self, file: Virtual) -> String {
+        match file {
+            Virtual::Service(Service::Metrics) => {
+                if let Some(store) = &self.store {
+                    METRICS.lock().unwrap().disk_cache_bytes = store.lock().unwrap().usage();
+                }
+                format!("{:#?}", *METRICS.lock().unwrap())
+            }
+            Virtual::Service(Service::Rejected) => library::rejected_report(&self.rejected),
+            Virtual::Service(Service::Limits) => limit::report(),
+            Virtual::Playlist(i) => {
+                let (depth, selection) = &self.playlists[i];
+                let prefix = "../".repeat(*depth);
+                let entries = selection
+                    .select(&self.tree, &self.paths)
+                    .into_iter()
+                    .map(|i| (&self.tree[i], format!("{}{}", prefix, self.paths[i])));
+                playlist::render(entries)
+            }
+            Virtual::Service(Service::Pinned) => {
+                let store = match &self.store {
+                    Some(store) => store.lock().unwrap(),
+                    None => return "pinning is disabled, set cache_dir\n".to_string(),
+                };
+                let mut lines = String::new();
+                let (mut pinned, mut complete) = (0, 0);
+                for ino in 2..2 + self.tree.len() as u64 {
+                    let id = self.track(ino).id.as_ref().unwrap();
+                    if !store.is_pinned(id) {
+                        continue;
+                    }
+                    let progress = store.progress(id);
+                    let length = self.attrs[&ino].size;
+                    pinned += 1;
+                    if progress.complete {
+                        complete += 1;
+                    }
+                    let percent = if progress.complete || length == 0 {
+                        100
+                    } else {
+                        (progress.downloaded.min(length) * 100 / length).min(99)
+                    };
+                    lines.push_str(&format!(
+                        "{:>3}% {}/{} {}\n",
+                        percent,
+                        progress.downloaded,
+                        length,
+                        self.path_of(ino)
+                    ));
+                }
+                format!(
+                    "pinned: {}\ncomplete: {}\ndisk_usage: {}\n{}",
+                    pinned,
+                    complete,
+                    store.usage(),
+                    lines
+                )
+            }
+        }
+    }
+
+    /// Remember the real length of a file, fixing the catalogue size if it was wrong.
+    fn learn_length(&mut self, ino: u64, id: &str, length: u64) {
+        self.buffer_length.insert(id.to_string(), length);
+        let attr = self.attrs.get_mut(&ino).unwrap();
+        if attr.size != length {
+            // fuse 0.3 can't send inode invalidation notices, so the kernel
+            // picks the new size up once the 1 second attr TTL expires.
+            warn!(
+                "{} - Size mismatch: library says {} but server has {}",
+                ino, attr.size, length
+            );
+            attr.size = length;
+            METRICS.lock().unwrap().size_mismatches += 1;
+        }
+    }
+
+    /// Take the length reported by the server into account.
+    fn check_length(&mut self, ino: u64, total: Option<u64>) {
+        if let Some(total) = total {
+            let id = self.tree[(ino - 2) as usize].id.clone().unwrap();
+            if self.buffer_length.get(&id) != Some(&total) {
+                self.learn_length(ino, &id, total);
+            }
+        }
+    }
+
+    /// With `trust_size` a read at the catalogue size may be short of the
+    /// real end of file. Ask the server before reporting EOF; true if the
+    /// file turned out to be longer.
+    fn probe_length(&mut self, ino: u64, client: &Client, url: &str, offset: i64) -> bool {
+        let id = self.track(ino).id.clone().unwrap();
+        if !self.options.trust_size
+            || offset < 0
+            || self.buffer_length.contains_key(&id)
+            || limit::exhausted().is_some()
+        {
+            return false;
+        }
+        let size = self.attrs[&ino].size;
+        let probe = Range {
+            start: offset as u64,
+            end: offset as u64 + 1,
+        };
+        match http::fetch_range(client, url, probe) {
+            Ok(fetched) => self.check_length(ino, fetched.total),
+            Err(err) => self.fetch_failed(ino, &err),
+        }
+        // Probe once, even if the server didn't tell the length.
+        if !self.buffer_length.contains_key(&id) {
+            self.buffer_length.insert(id.clone(), size);
+        }
+        self.buffer_length[&id] > size
+    }
+
+    fn fetch_failed(&self, ino: u64, err: &dyn fmt::Display) {
+        let name = &self.tree[(ino - 2) as usize].path.as_ref();
+        let basename = get_basename(*name).unwrap();
+        error!("An error fetching file {}. {}", basename, err);
+        METRICS.lock().unwrap().connect_errors += 1;
+    }
+
+    /// Read from a track as it is on the server.
+    fn read_track(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        debug!(
+            "{} - read(ino={}, fh={}, offset={}, size={}) ",
+            ino, ino, fh, offset, size
+        );
+
+        let url = &self.tree[(ino - 2) as usize].path.as_ref().unwrap();
+        let id = self.tree[(ino - 2) as usize].id.clone().unwrap();
+        let full_url = format!("{}{}", self.server, url);
+        let content_length: u64;
+        let client = Client::new();
+
+        // pinned and other complete files on disk
+        if let Some(store) = &self.store {
+            let mut store = store.lock().unwrap();
+            let progress = store.progress(&id);
+            if progress.complete {
+                let range = match range::read_range(offset, size, progress.length) {
+                    Some(range) => range,
+                    None => {
+                        return Ok(Vec::new());
+                    }
+                };
+                match store.read(&id, range) {
+                    Ok(chunk) => {
+                        debug!("{} - Hit disk cache", ino);
+                        METRICS.lock().unwrap().hit_disk_cache += 1;
+                        drop(store);
+                        self.queue_scan(ino, progress.length);
+                        return Ok(chunk);
+                    }
+                    Err(e) => error!("{} - Can't read from disk cache. {}", ino, e),
+                }
+            }
+        }
+
+        // content_length cache.
+        if self.buffer_length.contains_key(id.as_str()) {
+            content_length = self.buffer_length[id.as_str()];
+            debug!("{} - Hit length cache", ino);
+            METRICS.lock().unwrap().hit_len_cache += 1;
+        } else if self.options.trust_size {
+            // Real length is learned from Content-Range of the first GET.
+            content_length = self.attrs[&ino].size;
+        } else {
+            if let Some(quota) = limit::exhausted() {
+                error!(
+                    "{} - The {} quota is exhausted, file isn't cached",
+                    ino, quota
+                );
+                return Err(EDQUOT);
+            }
+            unsafe {
+                content_length = match client
+                    .head(full_url.as_str())
+                    .header("Authorization", format!("Basic {}", HTTP_AUTH))
+                    .send()
+                {
+                    Ok(content) => {
+                        let content_length = match content
+                            .headers()
+                            .get(CONTENT_LENGTH)
+                            .and_then(|header| header.to_str().ok())
+                            .and_then(|header| header.parse::<u64>().ok())
+                        {
+                            Some(content_length) => content_length,
+                            None => {
+                                return Err(EIO);
+                            }
+                        };
+                        content_length
+                    }
+                    Err(err) => {
+                        let name = &self.tree[(ino - 2) as usize].path.as_ref();
+                        let basename = get_basename(*name).unwrap();
+                        error!("An error fetching file {}. {}", basename, err);
+                        METRICS.lock().unwrap().connect_errors += 1;
+                        return Err(EIO);
+                    }
+                };
+            }
+            METRICS.lock().unwrap().http_requests += 1;
+            self.learn_length(ino, &id, content_length);
+            debug!("{} - Miss length cache", ino);
+            METRICS.lock().unwrap().miss_len_cache += 1;
+        }
+        let range = match range::read_range(offset, size, content_length) {
+            Some(range) => range,
+            None if self.probe_length(ino, &client, &full_url, offset) => {
+                return self.read_track(ino, fh, offset, size);
+            }
+            None => {
+                if offset < 0 || offset as u64 > content_length {
+                    // Wrong filesize detected.
+                    warn!(
+                        "{} - Wrong offset. Len is {} but offset {}",
+                        ino, content_length, offset
+                    );
+                }
+                return Ok(Vec::new());
+            }
+        };
+        debug!("{} - Content len {:?} ", ino, content_length);
+
+        // Besides the requested range prefetch whole file head and tail,
+        // where players and tag readers look for metadata.
+        let tail_range = range::tail_range(content_length, self.options.cache_tail);
+        let in_tail = tail_range.is_some_and(|tail| range.start >= tail.start);
+        let wanted = if range.end <= self.options.cache_head {
+            range::head_range(content_length, self.options.cache_head).unwrap()
+        } else if in_tail {
+            tail_range.unwrap()
+        } else {
+            range
+        };
+        self.prefetch_next(ino, fh, range, content_length);
+        let (missing, hit) = {
+            let cache = self.cache.lock().unwrap();
+            (
+                cache.missing(ino, content_length, wanted),
+                cache.missing(ino, content_length, range).is_empty(),
+            )
+        };
+        match (hit, in_tail) {
+            (true, false) => {
+                debug!("{} - Hit data cache", ino);
+                METRICS.lock().unwrap().hit_data_cache += 1;
+            }
+            (true, true) => {
+                debug!("{} - Hit tail cache", ino);
+                METRICS.lock().unwrap().hit_tail_cache += 1;
+            }
+            (false, false) => {
+                debug!("{} - Miss data cache", ino);
+                METRICS.lock().unwrap().miss_data_cache += 1;
+            }
+            (false, true) => {
+                debug!("{} - Miss tail cache", ino);
+                METRICS.lock().unwrap().miss_tail_cache += 1;
+            }
+        }
+        let missing = match limit::exhausted() {
+            Some(quota) if !missing.is_empty() => {
+                if !hit {
+                    error!(
+                        "{} - The {} quota is exhausted, data isn't cached",
+                        ino, quota
+                    );
+                    return Err(EDQUOT);
+                }
+                // Serve what is cached, don't fill the head or tail.
+                Vec::new()
+            }
+            _ => missing,
+        };
+        for run in missing {
+            // Tail is read once by tag readers, don't move the stream there.
+            let response = if in_tail {
+                self.fetch(ino, &client, &full_url, run)
+            } else {
+                self.fetch_streamed(ino, fh, &full_url, run)
+            };
+            let response = match response {
+                Some(response) => response,
+                None => {
+                    return Err(EIO);
+                }
+            };
+            // Length may have been corrected by the response.
+            let length = self.attrs[&ino].size;
+            let mut cache = self.cache.lock().unwrap();
+            cache.insert(ino, length, run.start, &response);
+            debug!("{} - Cached {} bytes of file", ino, cache.cached_bytes(ino));
+        }
+        let chunk = {
+            let mut cache = self.cache.lock().unwrap();
+            METRICS.lock().unwrap().cache_bytes = cache.total_bytes();
+            cache.read(ino, range)
+        };
+        if range.start < self.options.cache_head {
+            self.queue_scan(ino, self.attrs[&ino].size);
+        }
+        if (chunk.len() as u64) < range.len() && range.end <= self.attrs[&ino].size {
+            warn!(
+                "{} - Short read: got {} of {} bytes at {}",
+                ino,
+                chunk.len(),
+                range.len(),
+                range.start
+            );
+        }
+        debug!(
+            "{} - Len: {}, Chunk {} - {}",
+            ino,
+            chunk.len(),
+            range.start,
+            range.start + chunk.len() as u64
+        );
+        Ok(chunk)
+    }
+
+    /// Beginning of a track as it is on the server, from disk, cache or
+    /// fetched and cached.
+    fn original(&mut self, ino: u64, client: &Client, url: &str, head: Range) -> Option<Vec<u8>> {
+        let id = self.track(ino).id.clone().unwrap();
+        if let Some(store) = &self.store {
+            let mut store = store.lock().unwrap();
+            if store.is_complete(&id) {
+                return store.read(&id, head).ok();
+            }
+        }
+        let missing = {
+            let cache = self.cache.lock().unwrap();
+            cache.missing(ino, self.attrs[&ino].size, head)
+        };
+        if !missing.is_empty() && limit::exhausted().is_some() {
+            return None;
+        }
+        for run in missing {
+            let data = self.fetch(ino, client, url, run)?;
+            let length = self.attrs[&ino].size;
+            self.cache
+                .lock()
+                .unwrap()
+                .insert(ino, length, run.start, &data);
+        }
+        Some(self.cache.lock().unwrap().read(ino, head))
+    }
+
+    /// Beginning of a track with all of its tags, read as far as needed.
+    fn tag_head(&mut self, ino: u64) -> Option<Vec<u8>> {
+        let url = format!("{}{}", self.server, self.track(ino).path.as_ref().unwrap());
+        let client = Client::new();
+        let mut needed = self.options.cache_head;
+        loop {
+            let length = self.attrs[&ino].size;
+            let head = range::head_range(length, needed)?;
+            let data = self.original(ino, &client, &url, head)?;
+            match tags::region_end(&data) {
+                // Tags are longer than what was read.
+                Err(end) if end as u64 > needed && end as u64 <= length.min(MAX_TAGS) => {
+                    needed = end as u64
+                }
+                _ => return Some(data),
+            }
+        }
+    }
+
+    /// Find the tag region of `ino` and build the tag to serve instead.
+    /// Files whose format can't be retagged are served as they are. APEv2
+    /// and ID3v1 tags at the end are cut off.
+    fn prepare_splice(&mut self, ino: u64) {
+        let head = match self.tag_head(ino) {
+            Some(head) => head,
+            None => return,
+        };
+        match retag::splice(&head, self.track(ino)) {
+            Ok(Some(mut splice)) => {
+                // Reading the head told the real length, if it wasn't known.
+                let length = self.attrs[&ino].size;
+                let tail = Range {
+                    start: length.saturating_sub(retag::TAIL).max(splice.end),
+                    end: length,
+                };
+                let url = format!("{}{}", self.server, self.track(ino).path.as_ref().unwrap());
+                let stale = match self.original(ino, &Client::new(), &url, tail) {
+                    Some(tail) => retag::tail_tags_len(&tail),
+                    None => 0,
+                };
+                // A broken size mustn't cut into the audio before the tag.
+                if stale > 0 && length.saturating_sub(stale) >= splice.end {
+                    splice.stop = Some(length - stale);
+                }
+                debug!(
+                    "{} - Serving {} bytes of tags instead of {}",
+                    ino,
+                    splice.tag.len(),
+                    splice.end
+                );
+                self.splices.insert(ino, splice);
+            }
+            _ => {
+                self.unspliced.insert(ino);
+            }
+        }
+    }
+
+    /// Cut the track of a CUE sheet `ino` out of its file: WAV at the exact
+    /// sample, FLAC at the frame the track starts in, under a new header.
+    fn prepare_cue(&mut self, ino: u64) {
+        let part = self.cue_parts[&ino].clone();
+        let head = match self.tag_head(part.source) {
+            Some(head) => head,
+            None => return,
+        };
+        let length = self.attrs[&part.source].size;
+        let splice = if let Some(wav) = cue::wav(&head) {
+            let data_end = (wav.data_start + wav.data_len).min(length);
+            let at = |start: u64| {
+                (wav.data_start + cue::sample(start, wav.rate) * wav.block_align).min(data_end)
+            };
+            let start = at(part.start);
+            let stop = part.end.map_or(data_end, at).max(start);
+            Splice {
+                end: start,
+                tag: cue::wav_header(wav.fmt, stop - start),
+                stop: Some(stop),
+            }
+        } else if let Some(flac) = cue::flac(&head) {
+            let (start, first) = match self.flac_frame(part.source, &flac, part.start) {
+                Some(frame) => frame,
+                None => return,
+            };
+            let (stop, last) = match part.end {
+                Some(end) => match self.flac_frame(part.source, &flac, end) {
+                    Some(frame) => frame,
+                    None => return,
+                },
+                None => (length, flac.total),
+            };
+            // Seek table and cue sheet of the whole file don't fit the part.
+            let streaminfo = cue::streaminfo(flac.blocks[0].1, last.saturating_sub(first));
+            let mut blocks = vec![(0, streaminfo.as_slice())];
+            blocks.extend(
+                flac.blocks[1..]
+                    .iter()
+                    .filter(|(kind, _)| *kind != 3 && *kind != 5),
+            );
+            Splice {
+                end: start,
+                tag: retag::flac(&blocks, &part.track),
+                stop: Some(stop.max(start)),
+            }
+        } else {
+            warn!("{} - Can't cut tracks out of this format.", part.source);
+            return;
+        };
+        debug!(
+            "{} - Serving bytes {}..{:?} of {}",
+            ino, splice.end, splice.stop, part.source
+        );
+        self.splices.insert(ino, splice);
+    }
+
+    /// Offset and first sample of the FLAC frame holding CUE time `start`.
+    /// The search starts at the closest seek point, or at a guess from the
+    /// average bitrate, and walks frame headers from there.
+    fn flac_frame(&mut self, ino: u64, flac: &cue::Flac, start: u64) -> Option<(u64, u64)> {
+        let sample = cue::sample(start, flac.rate);
+        if sample == 0 {
+            return Some((flac.audio_start, 0));
+        }
+        let url = format!("{}{}", self.server, self.track(ino).path.as_ref().unwrap());
+        let client = Client::new();
+        let length = self.attrs[&ino].size;
+        let window = self.options.cache_block;
+        let mut from = match flac
+            .seek_points
+            .iter()
+            .rev()
+            .find(|(point, _)| *point <= sample)
+        {
+            Some((_, offset)) => flac.audio_start + offset,
+            None if flac.total > 0 => {
+                let audio = length.saturating_sub(flac.audio_start) as u128;
+                let guess = (audio * sample as u128 / flac.total as u128) as u64;
+                flac.audio_start + guess.saturating_sub(window / 2)
+            }
+            None => flac.audio_start,
+        };
+        for _ in 0..MAX_FRAME_WINDOWS {
+            let range = Range {
+                start: from,
+                end: (from + window).min(length),
+            };
+            if range.start >= range.end {
+                return None;
+            }
+            let data = self.original(ino, &client, &url, range)?;
+            let frames = cue::frames(&data, flac.block_size, range.end == length);
+            let before = frames.iter().rev().find(|(_, first)| *first <= sample);
+            let after = frames.iter().any(|(_, first)| *first > sample);
+            match (before, after) {
+                (Some(&(pos, first)), true) => return Some((from + pos as u64, first)),
+                // The guess went too far.
+                (None, true) if from > flac.audio_start => {
+                    from = from.saturating_sub(window).max(flac.audio_start)
+                }
+                (Some(&(pos, first)), false) if range.end == length => {
+                    return Some((from + pos as u64, first))
+                }
+                // Frames run on past the window.
+                (Some(&(pos, _)), false) if pos > 0 => from += pos as u64,
+                (_, false) => from = range.end,
+                (None, true) => return None,
+            }
+        }
+        None
+    }
+
+    /// Look for the content of sidecar `ino` unless it's known already.
+    /// Fails if it can't be told now whether there is any, e.g. while the
+    /// server is down or the quota is used up; it's looked for again later.
+    fn load_sidecar(&mut self, ino: u64) -> Result<(), ()> {
+        if self.sidecar_data.contains_key(&ino) {
+            return Ok(());
+        }
+        let data = match self.sidecars[&ino] {
+            (Sidecar::Cover, track) => self.find_cover(track)?,
+            (Sidecar::Lyrics, track) => self.find_lyrics(track)?,
+        };
+        let png = data
+            .as_ref()
+            .is_some_and(|data| data.starts_with(b"\x89PNG"));
+        if png {
+            for (old, new) in COVER_NAMES.iter().zip(PNG_COVER_NAMES.iter()) {
+                self.rename_entry(ino, old, new);
+            }
+        }
+        self.sidecar_data.insert(ino, data);
+        Ok(())
+    }
+
+    /// Give entry `old` of `ino` in its directory the name `new`, if it's free.
+    fn rename_entry(&mut self, ino: u64, old: &str, new: &str) {
+        let dir = self.parents[&ino];
+        let entries = self.inodes.get_mut(&dir).unwrap();
+        if entries.get(old) != Some(&ino) || entries.contains_key(new) {
+            return;
+        }
+        entries.remove(old);
+        entries.insert(new.to_string(), ino);
+        if let Some(ordered) = self.album_order.get_mut(&dir) {
+            for (name, _) in ordered.iter_mut().filter(|(name, _)| name == old) {
+                *name = new.to_string();
+            }
+        }
+        self.lookup_keys.remove(&dir);
+    }
+
+    /// GET `endpoint` of the server with `{id}` replaced by the id of `track`.
+    /// Nothing is there if the server says so, errors fail.
+    fn fetch_sidecar(&self, endpoint: &str, track: u64) -> Result<Option<Vec<u8>>, ()> {
+        if limit::exhausted().is_some() {
+            return Err(());
+        }
+        let id = self.track(track).id.as_ref().unwrap();
+        let id = utf8_percent_encode(id, NON_ALPHANUMERIC).to_string();
+        let url = format!("{}{}", self.server, endpoint.replace("{id}", &id));
+        match http::fetch(&Client::new(), &url) {
+            Ok(data) if !data.is_empty() => Ok(Some(data)),
+            Ok(_) => Ok(None),
+            Err(e) if http::not_found(&e) => {
+                debug!("{} - Nothing at {}", track, url);
+                Ok(None)
+            }
+            Err(e) => {
+                warn!("{} - Can't fetch {}. {}", track, url, e);
+                Err(())
+            }
+        }
+    }
+
+    /// Lyrics of `track` from the server or its tags.
+    /// Tags are looked at even if the server fails, but there are no lyrics
+    /// only if neither has any.
+    fn find_lyrics(&mut self, track: u64) -> Result<Option<Vec<u8>>, ()> {
+        let from_server = match &self.options.lyrics_endpoint {
+            Some(endpoint) => self.fetch_sidecar(endpoint, track),
+            None => Ok(None),
+        };
+        if let Ok(Some(lyrics)) = from_server {
+            return Ok(Some(lyrics));
+        }
+        let head = self.tag_head(track).ok_or(())?;
+        match tags::lyrics(&head) {
+            Some(lyrics) => Ok(Some(lyrics.into_bytes())),
+            None => from_server,
+        }
+    }
+
+    /// Cover of the album of `track`: from disk cache, the server or the
+    /// picture embedded in the track.
+    fn find_cover(&mut self, track: u64) -> Result<Option<Vec<u8>>, ()> {
+        let id = self.track(track).id.clone().unwrap();
+        if let Some(store) = &self.store {
+            if let Some(image) = store.lock().unwrap().cover(&id) {
+                return Ok(Some(image));
+            }
+        }
+        let from_server = match &self.options.cover_endpoint {
+            Some(endpoint) => self.fetch_sidecar(endpoint, track)?,
+            None => None,
+        };
+        let image = match from_server {
+            Some(image) => image,
+            None => match tags::picture(&self.tag_head(track).ok_or(())?) {
+                Some(image) => image,
+                None => return Ok(None),
+            },
+        };
+        if let Some(store) = &self.store {
+            if let Err(e) = store.lock().unwrap().save_cover(&id, &image) {
+                error!("Can't save cover to disk cache. {}", e);
+            }
+        }
+        Ok(Some(image))
+    }
+
+    /// Ranged GET of a track, learning its real length on the way.
+    fn fetch(&mut self, ino: u64, client: &Client, url: &str, range: Range) -> Option<Vec<u8>> {
+        match http::fetch_range(client, url, range) {
+            Ok(fetched) => {
+                self.check_length(ino, fetched.total);
+                Some(fetched.data)
+            }
+            Err(err) => {
+                self.fetch_failed(ino, &err);
+                None
+            }
+        }
+    }
+
+    /// Read `range` from the GET owned by handle `fh`. A new GET is sent only
+    /// when there is none yet or the handle was seeked elsewhere.
+    fn fetch_streamed(&mut self, ino: u64, fh: u64, url: &str, range: Range) -> Option<Vec<u8>> {
+        let mut stream = match self.handles.get_mut(&fh) {
+            Some(stream) => stream.take(),
+            None => return self.fetch(ino, &Client::new(), url, range),
+        };
+        // A stream kept open over a pause is often closed by the server in
+        // the meantime, give it one more try on a new connection.
+        for retry in [false, true] {
+            if stream.as_ref().map(|stream| stream.position()) != Some(range.start) {
+                if stream.is_some() {
+                    debug!("{} - Seek to {}, reconnecting", ino, range.start);
+                }
+                // Streams live as long as the file is open. The timeout is
+                // for each read from them, so a stalled server can't hang
+                // the mount.
+                let client = self.stream_client.get_or_insert_with(|| {
+                    Client::builder()
+                        .connect_timeout(STREAM_CONNECT_TIMEOUT)
+                        .timeout(STREAM_READ_TIMEOUT)
+                        .build()
+                        .unwrap()
+                });
+                stream = match Stream::open(client, url, range.start) {
+                    Ok(stream) => {
+                        self.check_length(ino, stream.total);
+                        Some(stream)
+                    }
+                    Err(err) if err.is::<stream::RangeIgnored>() => {
+                        debug!("{} - {}, fetching the range alone", ino, err);
+                        return self.fetch(ino, &Client::new(), url, range);
+                    }
+                    Err(err) => {
+                        self.fetch_failed(ino, &err);
+                        return None;
+                    }
+                };
+            }
+            let mut opened = stream.take().unwrap();
+            match opened.read(range.len()) {
+                Ok(data) if data.len() as u64 == range.len() || retry => {
+                    self.handles.insert(fh, Some(opened));
+                    return Some(data);
+                }
+                Ok(data) => debug!(
+                    "{} - Stream ended after {} bytes, reconnecting",
+                    ino,
+                    data.len()
+                ),
+                Err(err) if !retry => debug!("{} - Stream dropped, reconnecting. {}", ino, err),
+                Err(err) => {
+                    // Dropped, the next read reconnects.
+                    self.fetch_failed(ino, &err);
+                    return None;
+                }
+            }
         }
+        None
     }
 }
 
 #[cfg(target_family = "unix")]
 impl Filesystem for JsonFilesystem {
+    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
+        limit::foreground();
+        Ok(())
+    }
+
+    fn destroy(&mut self, _req: &Request) {
+        limit::flush();
+    }
+
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         debug!("getattr(ino={})", ino);
+        self.merge_lengths();
+        // Albums without art have no cover, tracks without lyrics no LRC.
+        if let Some(None) = self.sidecar_data.get(&ino) {
+            reply.error(ENOENT);
+            return;
+        }
+        let file = self.virtual_files.get(&ino).copied();
+        let content = file.map(|file| self.content(file));
         match self.attrs.get(&ino) {
             Some(attr) => {
-                let ttl = Timespec::new(1, 0);
-                reply.attr(&ttl, attr);
+                let ttl = self.attr_ttl(ino);
+                match content {
+                    Some(content) => {
+                        let size = content.len() as u64;
+                        reply.attr(&ttl, &FileAttr { size, ..*attr });
+                    }
+                    None => reply.attr(&ttl, &self.shown_attr(ino, attr)),
+                }
             }
             None => reply.error(ENOENT),
         };
     }
 
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        debug!("statfs()");
+        let (blocks, free, files, ffree) = self.fs_stats();
+        reply.statfs(
+            blocks,
+            free,
+            free,
+            files,
+            ffree,
+            FS_BLOCK as u32,
+            255,
+            FS_BLOCK as u32,
+        );
+    }
+
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         debug!("lookup(parent={}, name={})", parent, name.to_str().unwrap());
-        let inode = match self.inodes.get(name.to_str().unwrap()) {
+        self.merge_lengths();
+        if parent == self.search_dir {
+            let query = name.to_str().unwrap();
+            // File managers look for hidden files everywhere.
+            if query.starts_with('.') {
+                reply.error(ENOENT);
+                return;
+            }
+            let ino = self.search(query);
+            // Kernel asks again once the entry expires, by then results are stale.
+            let ttl = Timespec::new(self.options.search_ttl as i64, 0);
+            reply.entry(&ttl, &self.attrs[&ino], 0);
+            return;
+        }
+        let inode = match self.find_entry(parent, name.to_str().unwrap()) {
+            Some(inode) if self.sidecar_data.get(&inode) == Some(&None) => {
+                reply.error(ENOENT);
+                return;
+            }
             Some(inode) => inode,
             None => {
                 reply.error(ENOENT);
                 return;
             }
         };
-        match self.attrs.get(inode) {
+        match self.attrs.get(&inode) {
             Some(attr) => {
-                let ttl = Timespec::new(1, 0);
+                let ttl = self.attr_ttl(inode);
                 debug!("{:#?}", attr);
-                reply.entry(&ttl, attr, 0);
+                reply.entry(&ttl, &self.shown_attr(inode, attr), 0);
             }
             None => reply.error(ENOENT),
         };
@@ -268,200 +2338,219 @@ impl Filesystem for JsonFilesystem {
         size: u32,
         reply: ReplyData,
     ) {
-        // return usage statistics
-        if ino == self.metrics_inode {
-            unsafe {
-                let metrics_str = format!("{:#?}", METRICS);
-                reply.data(&metrics_str.as_bytes());
+        self.merge_tags();
+        self.merge_lengths();
+        // return usage statistics, reports, etc.
+        if let Some(&file) = self.virtual_files.get(&ino) {
+            let content = self.content(file);
+            let start = (offset.max(0) as usize).min(content.len());
+            let end = (start + size as usize).min(content.len());
+            reply.data(&content.as_bytes()[start..end]);
+            return;
+        }
+        if self.sidecars.contains_key(&ino) {
+            if self.load_sidecar(ino).is_err() {
+                reply.error(EIO);
+                return;
+            }
+            match &self.sidecar_data[&ino] {
+                Some(data) => {
+                    let start = (offset.max(0) as usize).min(data.len());
+                    let end = (start + size as usize).min(data.len());
+                    reply.data(&data[start..end]);
+                }
+                None => reply.error(ENOENT),
             }
             return;
         }
 
-        // cleaning cache
-        if self.buffer_head_index.len() > self.cache_max_count as usize {
-            let mut iter = self.buffer_head_index.iter().filter(|&x| *x != ino);
-            let old_entry = iter.next().unwrap();
-            self.buffer_head_data.remove(old_entry);
-            let old_entry_copy = old_entry.clone();
-            self.buffer_head_index.remove(&old_entry_copy);
-            let basename = &self.tree[(ino - 2) as usize].path.as_ref();
-            debug!(
-                "{} - Cache dropped for: {} ",
-                ino,
-                get_basename(*basename).unwrap().to_string()
-            );
+        let source = match self.cue_parts.get(&ino) {
+            Some(part) if self.splices.contains_key(&ino) => part.source,
+            Some(_) => {
+                reply.error(EIO);
+                return;
+            }
+            None => ino,
+        };
+        let data = match self.splices.get(&ino) {
+            Some(splice) => {
+                let (mut data, rest) = splice.map(offset.max(0) as u64, size as u64);
+                match rest {
+                    Some(rest) => self
+                        .read_track(source, fh, rest.start as i64, rest.len() as u32)
+                        .map(|rest| {
+                            data.extend(rest);
+                            data
+                        }),
+                    None => Ok(data),
+                }
+            }
+            None => self.read_track(ino, fh, offset, size),
+        };
+        match data {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
         }
-        debug!(
-            "{} - read(ino={}, fh={}, offset={}, size={}) ",
-            ino, ino, fh, offset, size
-        );
-
-        let url = &self.tree[(ino - 2) as usize].path.as_ref().unwrap();
-        let id = &self.tree[(ino - 2) as usize].id.as_ref().unwrap();
-        let full_url = format!("{}{}", self.server, url);
-        let chunk: Vec<u8>;
-        let content_length: i64;
-        let client = Client::new();
+    }
 
-        // content_length cache.
-        if self.buffer_length.contains_key(id.as_str()) {
-            content_length = self.buffer_length[id.as_str()];
-            debug!("{} - Hit length cache", ino);
-            unsafe {
-                METRICS.hit_len_cache += 1;
-            }
-        } else {
-            unsafe {
-                content_length = match client
-                    .head(full_url.as_str())
-                    .header("Authorization", format!("Basic {}", HTTP_AUTH))
-                    .send()
-                {
-                    Ok(content) => {
-                        let content_length = match content.headers().get(CONTENT_LENGTH) {
-                            Some(header_content) => {
-                                header_content.to_str().unwrap().parse::<i64>().unwrap()
-                            }
-                            None => {
-                                reply.error(EIO);
-                                return;
-                            }
-                        };
-                        content_length
-                    }
-                    Err(err) => {
-                        let name = &self.tree[(ino - 2) as usize].path.as_ref();
-                        let basename = get_basename(*name).unwrap().to_string();
-                        error!("An error fetching file {}. {}", basename, err);
-                        METRICS.connect_errors += 1;
-                        reply.error(EIO);
-                        return;
-                    }
-                };
+    fn open(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        debug!("open(ino={})", ino);
+        if self.virtual_files.contains_key(&ino) {
+            // Content changes all the time, so does its size.
+            reply.opened(0, FOPEN_DIRECT_IO);
+        } else if self.sidecars.contains_key(&ino) {
+            // Listed with no size before, direct reads aren't cut at it.
+            match self.load_sidecar(ino).map(|_| &self.sidecar_data[&ino]) {
+                Ok(Some(_)) => reply.opened(0, FOPEN_DIRECT_IO),
+                Ok(None) => reply.error(ENOENT),
+                Err(()) => reply.error(EIO),
             }
-            unsafe {
-                METRICS.http_requests += 1;
+        } else if self.is_track(ino) || self.cue_parts.contains_key(&ino) {
+            // Played again, maybe after the next track was evicted.
+            self.prefetched.remove(&ino);
+            if self.cue_parts.contains_key(&ino) {
+                if !self.splices.contains_key(&ino) {
+                    self.prepare_cue(ino);
+                }
+                if !self.splices.contains_key(&ino) {
+                    reply.error(EIO);
+                    return;
+                }
+            } else if self.options.rewrite_tags && !self.splices.contains_key(&ino) {
+                // Head and tail are fetched right here, other operations on
+                // the mount wait meanwhile. Cached or stored ones are quick.
+                self.prepare_splice(ino);
             }
-            self.buffer_length.insert(id.to_string(), content_length);
-            debug!("{} - Miss length cache", ino);
-            unsafe {
-                METRICS.miss_len_cache += 1;
+            let fh = self.next_fh;
+            self.next_fh += 1;
+            self.handles.insert(fh, None);
+            // The kernel may still hold the size from before the splice, direct
+            // reads aren't cut at it.
+            if self.splices.contains_key(&ino) {
+                reply.opened(fh, FOPEN_DIRECT_IO);
+            } else {
+                reply.opened(fh, 0);
             }
+        } else {
+            reply.opened(0, 0);
         }
-        // Check for API wrong file size here
-        if content_length > offset {
-            debug!("{} - Content len {:?} ", ino, content_length);
-            let end_of_chunk = if size - 1 + offset as u32 > content_length as u32 {
-                content_length
-            } else {
-                (size + offset as u32) as i64
-            };
-            let range = format!("bytes={}-{}", offset, end_of_chunk - 1);
-
-            // if it's beginning of file...
-            if end_of_chunk < self.cache_head as i64 {
-                // looking for CACHE_HEAD bytes file beginning in cache
-                if self.buffer_head_data.contains_key(&ino) {
-                    // Cache found
-                    debug!("{} - Hit data cache", ino);
-                    unsafe {
-                        METRICS.hit_data_cache += 1;
-                    }
-                    chunk = self.buffer_head_data[&ino][offset as usize..end_of_chunk as usize]
-                        .to_vec()
-                        .clone();
-                    reply.data(&chunk);
-                } else {
-                    // Cache doesn't found
-                    debug!("{} - Miss data cache", ino);
-                    unsafe {
-                        METRICS.miss_data_cache += 1;
-                    }
-                    // Fetch file head (CACHE_HEAD)
-                    let response: Vec<u8>;
-                    unsafe {
-                        response = match client
-                            .get(full_url.as_str())
-                            .header(
-                                "Range",
-                                format!(
-                                    "bytes=0-{}",
-                                    if self.cache_head as i64 > content_length {
-                                        content_length - 1
-                                    } else {
-                                        self.cache_head as i64 - 1
-                                    }
-                                ),
-                            )
-                            .header("Authorization", format!("Basic {}", HTTP_AUTH))
-                            .send()
-                        {
-                            Ok(content) => content.bytes().unwrap().to_vec(),
-                            Err(err) => {
-                                let name = &self.tree[(ino - 2) as usize].path.as_ref();
-                                let basename = get_basename(*name).unwrap().to_string();
-                                error!("An error fetching file {}. {}", basename, err);
-                                METRICS.connect_errors += 1;
-                                reply.error(EIO);
-                                return;
-                            }
-                        };
-                    }
-                    unsafe {
-                        METRICS.http_requests += 1;
-                        METRICS.ingress += response.len() as u64;
-                    }
-                    // Save cache
-                    self.buffer_head_data.insert(ino, response.to_vec());
-                    self.buffer_head_index.insert(ino);
-                    chunk = response[offset as usize..end_of_chunk as usize].to_vec();
-                    reply.data(&chunk);
-                }
-                debug!("{} - Chunk len: {:?} ", ino, chunk.len());
-                return;
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        _size: Option<u64>,
+        _atime: Option<Timespec>,
+        _mtime: Option<Timespec>,
+        _fh: Option<u64>,
+        _crtime: Option<Timespec>,
+        _chgtime: Option<Timespec>,
+        _bkuptime: Option<Timespec>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        debug!("setattr(ino={})", ino);
+        // Shells truncate LIMITS.TXT before writing to it, which is a no-op.
+        match (self.virtual_files.get(&ino), self.attrs.get(&ino)) {
+            (Some(Virtual::Service(Service::Limits)), Some(attr)) if owns(req) => {
+                reply.attr(&Timespec::new(1, 0), attr)
             }
-            // If it isn't a beginning of file don't cache it and fetch over HTTP directly.
-            let response: Vec<u8>;
-            unsafe {
-                response = match client
-                    .get(full_url.as_str())
-                    .header("Range", &range)
-                    .header("Authorization", format!("Basic {}", HTTP_AUTH))
-                    .send()
-                {
-                    Ok(content) => content.bytes().unwrap().to_vec(),
-                    Err(err) => {
-                        let name = &self.tree[(ino - 2) as usize].path.as_ref();
-                        let basename = get_basename(*name).unwrap().to_string();
-                        error!("An error fetching file {}. {}", basename, err);
-                        METRICS.connect_errors += 1;
-                        reply.error(EIO);
-                        return;
-                    }
-                };
+            _ => reply.error(EACCES),
+        }
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _fh: u64,
+        _offset: i64,
+        data: &[u8],
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        debug!("write(ino={}, len={})", ino, data.len());
+        if self.virtual_files.get(&ino) != Some(&Virtual::Service(Service::Limits)) || !owns(req) {
+            reply.error(EACCES);
+            return;
+        }
+        let mut limits = limit::LIMITS.lock().unwrap();
+        for line in String::from_utf8_lossy(data).lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
             }
-            unsafe {
-                METRICS.http_requests += 1;
-                METRICS.ingress += response.len() as u64;
+            if let Err(e) = limits.apply(line) {
+                error!("{}", e);
+                reply.error(EINVAL);
+                return;
             }
-            chunk = response.to_vec().clone();
-            reply.data(&chunk);
-            debug!(
-                "{} - Len: {}, Chunk {} - {}",
-                ino,
-                chunk.len(),
-                offset,
-                offset + chunk.len() as i64
-            );
+        }
+        reply.written(data.len() as u32);
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        debug!("release(ino={}, fh={})", ino, fh);
+        self.handles.remove(&fh);
+        self.playback.remove(&fh);
+        reply.ok();
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!("setxattr(ino={}, name={:?})", ino, name);
+        if !owns(req) {
+            reply.error(EACCES);
+            return;
+        }
+        match self.set_xattr(ino, name, value) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("removexattr(ino={}, name={:?})", ino, name);
+        if !owns(req) {
+            reply.error(EACCES);
+        } else if name != XATTR_PIN {
+            reply.error(ENODATA);
+        } else if self.set_pinned(ino, false) {
+            reply.ok();
         } else {
-            // Wrong filesize detected.
-            warn!(
-                "{} - Wrong offset. Len is {} but offset {}",
-                ino, content_length, offset
-            );
-            reply.data(&[]);
+            reply.error(ENOTSUP);
         }
-        return;
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!("getxattr(ino={}, name={:?})", ino, name);
+        reply_xattr(reply, self.get_xattr(ino, name, size));
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr(ino={})", ino);
+        reply_xattr(reply, self.list_xattr(ino, size));
     }
 
     fn readdir(
@@ -473,21 +2562,34 @@ impl Filesystem for JsonFilesystem {
         mut reply: ReplyDirectory,
     ) {
         debug!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
-        if ino == 1 {
-            if offset == 0 {
-                reply.add(1, 0, FileType::Directory, ".");
-                reply.add(1, 1, FileType::Directory, "..");
+        let entries = match self.inodes.get(&ino) {
+            Some(entries) => entries,
+            None => {
+                reply.error(ENOENT);
+                return;
             }
-            for (i, (key, &inode)) in self.inodes.iter().enumerate().skip(offset as usize) {
-                if inode == 1 {
-                    continue;
-                }
-                reply.add(inode, (i + 1) as i64, FileType::RegularFile, key);
+        };
+        if offset == 0 {
+            reply.add(ino, 1, FileType::Directory, ".");
+            reply.add(self.parents[&ino], 2, FileType::Directory, "..");
+        }
+        let entries: Box<dyn Iterator<Item = (&String, &u64)>> = match self.album_order.get(&ino) {
+            Some(ordered) => Box::new(ordered.iter().map(|(name, inode)| (name, inode))),
+            None => Box::new(entries.iter()),
+        };
+        for (i, (key, &inode)) in entries
+            .enumerate()
+            .skip((offset as usize).saturating_sub(2))
+        {
+            // Sidecars turned out to have nothing in them.
+            if let Some(None) = self.sidecar_data.get(&inode) {
+                continue;
+            }
+            if reply.add(inode, (i + 3) as i64, self.attrs[&inode].kind, key) {
+                break;
             }
-            reply.ok();
-        } else {
-            reply.error(ENOENT);
         }
+        reply.ok();
     }
 }
 
@@ -534,6 +2636,14 @@ fn main() {
                 .required(false)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("cache_max_size")
+                .long("cache-max-size")
+                .value_name("MiB")
+                .help("How many MiB of file data store in cache. [default: 512]")
+                .required(false)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("cache_head")
                 .long("cache-head")
@@ -542,6 +2652,36 @@ fn main() {
                 .required(false)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("cache_tail")
+                .long("cache-tail")
+                .value_name("KiB")
+                .help("How many KiB cache in file end for speeding up tag readers. [default: 128]")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cache_dir")
+                .long("cache-dir")
+                .value_name("PATH")
+                .help("Directory for disk cache with pinned files. Pinning is off if not set.")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trust_size")
+                .long("trust-size")
+                .help("Trust file sizes from the library instead of asking the server for each file.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("name_collision")
+                .long("name-collision")
+                .value_name("STRATEGY")
+                .help("How to rename tracks with clashing names: artist, album, id or hierarchy. [default: album]")
+                .required(false)
+                .takes_value(true),
+        )
         .get_matches();
 
     info!("Logger initialized. Set RUST_LOG=[debug,error,info,warn,trace] Default: info");
@@ -571,10 +2711,7 @@ fn main() {
             String::new()
         }
     };
-    let http_pass = match settings.get_str("http_pass") {
-        Ok(u) => u,
-        Err(_) => String::new(),
-    };
+    let http_pass = settings.get_str("http_pass").unwrap_or_default();
     let server = match settings.get_str("server") {
         Ok(server_cfg) => match cli_args.value_of("server") {
             Some(server_opt) => server_opt.to_string(),
@@ -601,6 +2738,7 @@ fn main() {
             }
         },
     };
+    let defaults = Options::default();
     let cache_head = match settings.get_str("cache_head") {
         Ok(cache_head_cfg) => match cli_args.value_of("cache_head") {
             Some(cache_head_opt) => 1024 * cache_head_opt.parse::<u64>().unwrap(),
@@ -608,7 +2746,17 @@ fn main() {
         },
         Err(_) => match cli_args.value_of("cache_head") {
             Some(cache_head_opt) => 1024 * cache_head_opt.parse::<u64>().unwrap(),
-            None => 768 * 1024,
+            None => defaults.cache_head,
+        },
+    };
+    let cache_tail = match settings.get_str("cache_tail") {
+        Ok(cache_tail_cfg) => match cli_args.value_of("cache_tail") {
+            Some(cache_tail_opt) => 1024 * cache_tail_opt.parse::<u64>().unwrap(),
+            None => 1024 * cache_tail_cfg.parse::<u64>().unwrap(),
+        },
+        Err(_) => match cli_args.value_of("cache_tail") {
+            Some(cache_tail_opt) => 1024 * cache_tail_opt.parse::<u64>().unwrap(),
+            None => defaults.cache_tail,
         },
     };
     let cache_max_count = match settings.get_str("cache_max_count") {
@@ -618,33 +2766,217 @@ fn main() {
         },
         Err(_) => match cli_args.value_of("cache_max_count") {
             Some(cache_max_count_opt) => cache_max_count_opt.parse::<u64>().unwrap(),
-            None => 10,
+            None => defaults.cache_max_count,
+        },
+    };
+    let name_collision = match cli_args
+        .value_of("name_collision")
+        .map(|opt| opt.to_string())
+        .or_else(|| settings.get_str("name_collision").ok())
+    {
+        Some(strategy) => match strategy.parse::<Disambiguation>() {
+            Ok(strategy) => strategy,
+            Err(e) => {
+                error!("{}", e);
+                process::exit(0x0001)
+            }
+        },
+        None => defaults.name_collision,
+    };
+    let cache_max_size = match settings.get_str("cache_max_size") {
+        Ok(cache_max_size_cfg) => match cli_args.value_of("cache_max_size") {
+            Some(cache_max_size_opt) => 1024 * 1024 * cache_max_size_opt.parse::<u64>().unwrap(),
+            None => 1024 * 1024 * cache_max_size_cfg.parse::<u64>().unwrap(),
+        },
+        Err(_) => match cli_args.value_of("cache_max_size") {
+            Some(cache_max_size_opt) => 1024 * 1024 * cache_max_size_opt.parse::<u64>().unwrap(),
+            None => defaults.cache_max_size,
+        },
+    };
+    let cache_block = match settings.get_int("cache_block") {
+        Ok(cache_block) if cache_block > 0 => 1024 * cache_block as u64,
+        _ => defaults.cache_block,
+    };
+    let trust_size =
+        cli_args.is_present("trust_size") || settings.get_bool("trust_size").unwrap_or(false);
+    let cache_dir = match cli_args.value_of("cache_dir") {
+        Some(cache_dir_opt) => Some(PathBuf::from(cache_dir_opt)),
+        None => settings.get_str("cache_dir").ok().map(PathBuf::from),
+    };
+    let cache_dir_max_size = match settings.get_int("cache_dir_max_size") {
+        Ok(cache_dir_max_size) if cache_dir_max_size > 0 => 1024 * 1024 * cache_dir_max_size as u64,
+        _ => defaults.cache_dir_max_size,
+    };
+    let prefetch = match settings.get_str("prefetch") {
+        Ok(prefetch) => match prefetch.parse::<Prefetch>() {
+            Ok(prefetch) => prefetch,
+            Err(e) => {
+                error!("{}", e);
+                process::exit(0x0001)
+            }
+        },
+        Err(_) => defaults.prefetch,
+    };
+    let prefetch_at = match settings.get_float("prefetch_at") {
+        Ok(prefetch_at) => prefetch_at.clamp(0.0, 1.0),
+        Err(_) => defaults.prefetch_at,
+    };
+    let download_connections = match settings.get_int("download_connections") {
+        Ok(connections) => connections.max(1) as usize,
+        Err(_) => defaults.download_connections,
+    };
+    let max_connections = match settings.get_int("max_connections") {
+        Ok(connections) => connections.max(1) as usize,
+        Err(_) => defaults.max_connections,
+    };
+    {
+        let mut limits = limit::LIMITS.lock().unwrap();
+        for (key, name) in &[
+            ("rate_limit", "rate"),
+            ("daily_quota", "daily"),
+            ("monthly_quota", "monthly"),
+        ] {
+            if let Ok(value) = settings.get_str(key) {
+                if let Err(e) = limits.apply(&format!("{} {}", name, value)) {
+                    error!("{}", e);
+                    process::exit(0x0001)
+                }
+            }
+        }
+    }
+    if let Some(dir) = &cache_dir {
+        limit::persist(dir);
+    }
+    let search_ttl = match settings.get_int("search_ttl") {
+        Ok(ttl) => ttl.max(1) as u64,
+        Err(_) => defaults.search_ttl,
+    };
+    let path_template = match settings.get_str("path_template") {
+        Ok(template) => match template.parse::<Template>() {
+            Ok(template) => Some(template),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(0x0001)
+            }
         },
+        Err(_) => None,
+    };
+    let safe_names = settings.get_bool("safe_names").unwrap_or(false);
+    let case_insensitive = settings.get_bool("case_insensitive").unwrap_or(false);
+    let scan_tags = settings.get_bool("scan_tags").unwrap_or(false);
+    let rewrite_tags = settings.get_bool("rewrite_tags").unwrap_or(false);
+    let covers = settings.get_bool("covers").unwrap_or(false);
+    let cover_endpoint = settings.get_str("cover_endpoint").ok();
+    let lyrics = settings.get_bool("lyrics").unwrap_or(false);
+    let lyrics_endpoint = settings.get_str("lyrics_endpoint").ok();
+    let cue_split = settings.get_bool("cue_split").unwrap_or(false);
+    let cover_cache_size = match settings.get_int("cover_cache_size") {
+        Ok(cover_cache_size) if cover_cache_size > 0 => 1024 * 1024 * cover_cache_size as u64,
+        _ => defaults.cover_cache_size,
+    };
+    let pins = match settings.get_array("pins") {
+        Ok(pins) => pins
+            .into_iter()
+            .filter_map(|pin| pin.into_str().ok())
+            .collect(),
+        Err(_) => Vec::new(),
     };
 
     unsafe {
-        METRICS.server_addr = server.clone();
+        METRICS.lock().unwrap().server_addr = server.clone();
         let mut buf = String::new();
         buf.push_str(&http_user);
-        buf.push_str(":");
+        buf.push(':');
         buf.push_str(&http_pass);
         HTTP_AUTH = base64::encode(buf)
     }
-    let lib = match get_tracks(&server) {
+    let library = match get_tracks(&server) {
         Ok(library) => library,
         Err(err) => {
-            error!("Can't fetch library from remote server. Probably server is not running or auth failed. {}", err);
-            error!(
-                "Provide Basic Auth credentials by setting envs MUS_HTTP_USER and MUS_HTTP_PASS or providing config.",
-            );
+            let status = err
+                .downcast_ref::<reqwest::Error>()
+                .and_then(|err| err.status());
+            if let Some(StatusCode::UNAUTHORIZED) | Some(StatusCode::FORBIDDEN) = status {
+                error!("Server turned the credentials down. {}", err);
+                error!(
+                    "Provide Basic Auth credentials by setting envs MUS_HTTP_USER and MUS_HTTP_PASS or providing config.",
+                );
+            } else {
+                error!(
+                    "Can't fetch library from remote server. Probably server is not running. {}",
+                    err
+                );
+            }
             process::exit(0x0001)
         }
     };
+    let (mut lib, rejected) = library::parse_tracks(&library);
+    let known_tags = match (&cache_dir, scan_tags) {
+        (Some(dir), true) => scan::load(dir),
+        _ => HashMap::new(),
+    };
+    for track in lib.iter_mut() {
+        if let Some(tags) = known_tags.get(track.id.as_ref().unwrap()) {
+            tags.fill(track);
+        }
+    }
+    // Not every server has playlists.
+    let server_playlists = match get_playlists(&server) {
+        Ok(playlists) => playlist::parse_server_playlists(&playlists),
+        Err(err) => {
+            warn!("Can't fetch playlists from remote server. {}", err);
+            Vec::new()
+        }
+    };
     info!("Remote library host: {}", &server);
-    let fs = JsonFilesystem::new(&lib, server, cache_max_count, cache_head);
+    let options = Options {
+        cache_max_count,
+        cache_head,
+        cache_tail,
+        cache_block,
+        cache_max_size,
+        name_collision,
+        trust_size,
+        cache_dir,
+        cache_dir_max_size,
+        pins,
+        prefetch,
+        prefetch_at,
+        download_connections,
+        max_connections,
+        search_ttl,
+        path_template,
+        safe_names,
+        case_insensitive,
+        scan_tags,
+        rewrite_tags,
+        covers,
+        cover_endpoint,
+        cover_cache_size,
+        lyrics,
+        lyrics_endpoint,
+        cue_split,
+    };
+    let cue_sheets = if cue_split {
+        read_cue_sheets(&server, &lib)
+    } else {
+        Vec::new()
+    };
+    let mut fs = JsonFilesystem::new(
+        &lib,
+        rejected,
+        &server_playlists,
+        &cue_sheets,
+        server,
+        options,
+    );
+    fs.start_pinning();
+    fs.start_prefetch();
+    fs.start_scanning(known_tags);
+    // The mount stays writable for LIMITS.TXT and pinning xattrs, the
+    // filesystem turns down any other change and changes by other users,
+    // see `owns`.
     let options = [
-        "-o",
-        "ro",
         "-o",
         "fsname=musfs",
         "-o",
@@ -653,16 +2985,23 @@ fn main() {
         "auto_unmount",
         "-o",
         "allow_other",
-    ]
-    .iter()
-    .map(|o| o.as_ref())
-    .collect::<Vec<&OsStr>>();
+    ];
+    let options = options.iter().map(|o| o.as_ref()).collect::<Vec<&OsStr>>();
 
     info!(
         "Caching {}B in head of files.",
-        SizeFormatterBinary::new(cache_head as u64)
+        SizeFormatterBinary::new(cache_head)
+    );
+    info!(
+        "Caching {}B in tail of files.",
+        SizeFormatterBinary::new(cache_tail)
+    );
+    info!(
+        "Max cache is {} files, {}B in {}B blocks.",
+        cache_max_count,
+        SizeFormatterBinary::new(cache_max_size),
+        SizeFormatterBinary::new(cache_block)
     );
-    info!("Max cache is {} files.", cache_max_count);
     info!("Fuse mount options: {:?}", options);
     let _mount: fuse::BackgroundSession;
     unsafe {
@@ -670,6 +3009,7 @@ fn main() {
     }
     ctrlc::set_handler(move || {
         println!("Exitting...");
+        limit::flush();
         process::exit(0x0000);
     })
     .expect("Error setting Ctrl-C handler");
@@ -677,3 +3017,150 @@ fn main() {
         sleep(Duration::from_millis(300));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn options() -> Options {
+        Options {
+            prefetch: Prefetch::Off,
+            ..Options::default()
+        }
+    }
+
+    fn filesystem(options: Options) -> JsonFilesystem {
+        let values: Vec<Value> = ["Café.mp3", "Björk.mp3", "AC:DC.mp3"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                json!({"id": i.to_string(), "name": &name[..name.len() - 4], "path": format!("/{}", name), "size": 1})
+            })
+            .collect();
+        let (tracks, rejected) = library::parse_tracks(&values);
+        JsonFilesystem::new(&tracks, rejected, &[], &[], String::new(), options)
+    }
+
+    #[test]
+    fn entries_match_other_forms() {
+        let mut fs = filesystem(options());
+        let cafe = fs.find_entry(1, "Café.mp3").unwrap();
+        assert_eq!(fs.find_entry(1, "Cafe\u{301}.mp3"), Some(cafe));
+        assert_eq!(fs.find_entry(1, "CAFÉ.mp3"), None);
+        assert_eq!(fs.find_entry(1, "Cafe.mp3"), None);
+
+        let mut fs = filesystem(Options {
+            case_insensitive: true,
+            safe_names: true,
+            ..options()
+        });
+        assert_eq!(fs.find_entry(1, "CAFE\u{301}.MP3"), Some(cafe));
+        let acdc = fs.find_entry(1, "AC_DC.mp3").unwrap();
+        assert_eq!(fs.find_entry(1, "ac:dc.mp3"), Some(acdc));
+    }
+
+    #[test]
+    fn search_entries_follow_changes() {
+        let mut fs = filesystem(Options {
+            case_insensitive: true,
+            search_ttl: 0,
+            ..options()
+        });
+        let search_dir = fs.search_dir;
+        let cafe = fs.search("cafe");
+        assert_eq!(fs.find_entry(search_dir, "CAFE"), Some(cafe));
+        assert!(fs.find_entry(cafe, "café.MP3").is_some());
+        // The next search drops expired results and their lookup keys.
+        fs.search("bjork");
+        assert_eq!(fs.find_entry(search_dir, "CAFE"), None);
+        assert!(fs.find_entry(search_dir, "Bjork").is_some());
+        // Searching again gives the same directory.
+        assert_eq!(fs.search("cafe"), cafe);
+    }
+
+    #[test]
+    fn virtual_files() {
+        let mut fs = filesystem(options());
+        let m3u = fs.find_entry(1, PLAYLISTS_DIR).unwrap();
+        let all = fs.find_entry(m3u, "all.m3u8").unwrap();
+        let file = fs.virtual_files[&all];
+        let content = fs.content(file);
+        assert!(content.starts_with("#EXTM3U\n"));
+        assert_eq!(content.matches("#EXTINF").count(), 3);
+        // Rendered once, the same content is served again.
+        assert!(Arc::ptr_eq(&content, &fs.content(file)));
+        let rejected = fs.find_entry(1, "REJECTED.TXT").unwrap();
+        let file = fs.virtual_files[&rejected];
+        assert_eq!(*fs.content(file), "rejected: 0\n");
+    }
+
+    #[test]
+    fn stats() {
+        let mut fs = filesystem(options());
+        assert_eq!(fs.fs_stats(), (1, 0, 3, 0));
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(dir.path(), 1 << 20, 0).unwrap();
+        std::fs::write(store.part_path("0"), [0]).unwrap();
+        store.finish("0").unwrap();
+        fs.store = Some(Arc::new(Mutex::new(store)));
+        assert_eq!(fs.fs_stats(), (1, 1, 3, 2));
+    }
+
+    #[test]
+    fn learned_lengths() {
+        let mut fs = filesystem(options());
+        let cafe = fs.find_entry(1, "Café.mp3").unwrap();
+        assert_eq!(fs.attrs[&cafe].size, 1);
+        fs.length_sender.send((cafe, 5)).unwrap();
+        fs.merge_lengths();
+        assert_eq!(fs.attrs[&cafe].size, 5);
+        assert_eq!(fs.buffer_length.get("0"), Some(&5));
+    }
+
+    #[test]
+    fn xattrs() {
+        let mut fs = filesystem(options());
+        let cafe = fs.find_entry(1, "Café.mp3").unwrap();
+        let id = OsStr::new("user.mus.id");
+        // Size probe first, then a buffer of that size.
+        assert_eq!(fs.get_xattr(cafe, id, 0), Ok(Xattr::Size(1)));
+        assert_eq!(fs.get_xattr(cafe, id, 1), Ok(Xattr::Data(b"0".to_vec())));
+        let names = match fs.list_xattr(cafe, 0) {
+            Ok(Xattr::Size(size)) => size,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(fs.list_xattr(cafe, names - 1), Err(ERANGE));
+        match fs.list_xattr(cafe, names) {
+            Ok(Xattr::Data(data)) => assert!(data.starts_with(b"user.mus.id\0")),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(
+            fs.get_xattr(cafe, OsStr::new("user.other"), 0),
+            Err(ENODATA)
+        );
+        // Pins need the disk cache.
+        let pin = OsStr::new(XATTR_PIN);
+        assert_eq!(fs.get_xattr(cafe, pin, 0), Err(ENODATA));
+        assert_eq!(fs.set_xattr(cafe, pin, b"1"), Err(ENOTSUP));
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), 1 << 20, 0).unwrap();
+        fs.store = Some(Arc::new(Mutex::new(store)));
+        assert_eq!(fs.get_xattr(cafe, pin, 1), Ok(Xattr::Data(b"0".to_vec())));
+        assert_eq!(fs.set_xattr(cafe, pin, b"maybe"), Err(EINVAL));
+        assert_eq!(
+            fs.set_xattr(cafe, OsStr::new("user.mus.id"), b"1"),
+            Err(ENOTSUP)
+        );
+        assert_eq!(fs.set_xattr(cafe, pin, b"yes\n"), Ok(()));
+        assert_eq!(fs.get_xattr(cafe, pin, 1), Ok(Xattr::Data(b"1".to_vec())));
+        // The root holds other tracks too, which aren't pinned.
+        assert_eq!(fs.get_xattr(1, pin, 1), Ok(Xattr::Data(b"0".to_vec())));
+        assert_eq!(fs.set_xattr(1, pin, b"1"), Ok(()));
+        assert_eq!(fs.get_xattr(1, pin, 1), Ok(Xattr::Data(b"1".to_vec())));
+        assert_eq!(fs.set_xattr(cafe, pin, b"0"), Ok(()));
+        assert_eq!(fs.get_xattr(cafe, pin, 1), Ok(Xattr::Data(b"0".to_vec())));
+        assert_eq!(fs.get_xattr(1, pin, 1), Ok(Xattr::Data(b"0".to_vec())));
+    }
+}
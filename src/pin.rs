@@ -0,0 +1,233 @@
+//! Background downloads of pinned tracks into the disk store.
+
+use crate::{
+    http::{self, Connections},
+    limit,
+    range::Range,
+    store::Store,
+    HTTP_AUTH, METRICS,
+};
+use reqwest::{blocking::Client, header::RANGE, StatusCode};
+use std::{
+    error::Error,
+    fs::OpenOptions,
+    io::{Read, Write},
+    sync::{
+        mpsc::{channel, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+const ATTEMPTS: u64 = 3;
+const BUFFER: usize = 64 * 1024;
+/// Size of pieces fetched in parallel.
+const PIECE: u64 = 4 * 1024 * 1024;
+
+/// A track to download completely.
+pub struct Job {
+    pub ino: u64,
+    pub id: String,
+    pub url: String,
+    pub length: u64,
+}
+
+/// Start the download thread. Jobs are handled one at a time in order.
+/// Lengths of downloaded files other than the job's go to `lengths`.
+pub fn spawn(
+    store: Arc<Mutex<Store>>,
+    connections: Arc<Connections>,
+    lengths: Sender<(u64, u64)>,
+) -> Sender<Job> {
+    let (sender, receiver) = channel::<Job>();
+    thread::Builder::new()
+        .name("pin".to_string())
+        .spawn(move || {
+            // Whole files take longer than the default request timeout.
+            let client = Client::builder().timeout(None).build().unwrap();
+            for job in receiver {
+                for attempt in 1..=ATTEMPTS {
+                    match download(&client, &store, &connections, &lengths, &job) {
+                        Ok(()) => break,
+                        Err(e) => {
+                            error!(
+                                "An error pinning {} (attempt {}/{}). {}",
+                                job.url, attempt, ATTEMPTS, e
+                            );
+                            METRICS.lock().unwrap().connect_errors += 1;
+                            thread::sleep(Duration::from_secs(5 * attempt));
+                        }
+                    }
+                }
+            }
+        })
+        .expect("Couldn't start pin thread");
+    sender
+}
+
+fn download(
+    client: &Client,
+    store: &Mutex<Store>,
+    connections: &Connections,
+    lengths: &Sender<(u64, u64)>,
+    job: &Job,
+) -> Result<(), Box<dyn Error>> {
+    let path = {
+        let store = store.lock().unwrap();
+        if !store.is_pinned(&job.id) || store.is_complete(&job.id) {
+            return Ok(());
+        }
+        store.part_path(&job.id)
+    };
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let mut downloaded = file.metadata()?.len();
+    if job.length > downloaded + PIECE {
+        // Length is known, fetch pieces in parallel and append them in order.
+        info!("Pinning {} from byte {} in pieces", job.url, downloaded);
+        let range = Range {
+            start: downloaded,
+            end: job.length,
+        };
+        let mut unpinned = false;
+        let mut length = job.length;
+        http::fetch_parallel(
+            client,
+            &job.url,
+            range,
+            job.length,
+            PIECE,
+            connections,
+            |_, fetched| {
+                length = fetched.total.unwrap_or(length);
+                file.write_all(&fetched.data)?;
+                downloaded += fetched.data.len() as u64;
+                let mut store = store.lock().unwrap();
+                store.set_progress(&job.id, downloaded, length);
+                unpinned = !store.is_pinned(&job.id);
+                Ok(!unpinned)
+            },
+        )?;
+        if unpinned {
+            info!("{} was unpinned, download stopped.", job.url);
+            return Ok(());
+        }
+    } else if job.length == 0 || downloaded < job.length {
+        if let Some(quota) = limit::exhausted() {
+            return Err(format!("The {} quota is exhausted", quota).into());
+        }
+        info!("Pinning {} from byte {}", job.url, downloaded);
+        let mut response;
+        unsafe {
+            response = client
+                .get(job.url.as_str())
+                .header(RANGE, format!("bytes={}-", downloaded))
+                .header("Authorization", format!("Basic {}", HTTP_AUTH))
+                .send()?;
+        }
+        METRICS.lock().unwrap().http_requests += 1;
+        if response.status() == StatusCode::OK && downloaded > 0 {
+            // Server ignored Range, start over.
+            file.set_len(0)?;
+            downloaded = 0;
+        }
+        if response.status() != StatusCode::RANGE_NOT_SATISFIABLE {
+            response = response.error_for_status()?;
+            let mut buffer = vec![0; BUFFER];
+            loop {
+                let n = response.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                file.write_all(&buffer[..n])?;
+                downloaded += n as u64;
+                limit::consumed(n as u64);
+                let mut store = store.lock().unwrap();
+                store.set_progress(&job.id, downloaded, job.length);
+                if !store.is_pinned(&job.id) {
+                    info!("{} was unpinned, download stopped.", job.url);
+                    return Ok(());
+                }
+            }
+        }
+    }
+    file.sync_all()?;
+    let length = {
+        let mut store = store.lock().unwrap();
+        store.finish(&job.id)?;
+        store.progress(&job.id).length
+    };
+    if length != job.length {
+        lengths.send((job.ino, length)).ok();
+    }
+    info!("Pinned {}", job.url);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, net::TcpListener};
+
+    /// URL of a server answering one request with `response`, and the
+    /// request it got.
+    fn serve(response: &'static [u8]) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/track.mp3", listener.local_addr().unwrap());
+        let request = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut byte = [0];
+            while !request.ends_with(b"\r\n\r\n") && socket.read(&mut byte).unwrap() == 1 {
+                request.push(byte[0]);
+            }
+            socket.write_all(response).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, request)
+    }
+
+    fn pin(response: &'static [u8], part: &[u8]) -> (Vec<u8>, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Mutex::new(Store::open(dir.path(), 100, 100).unwrap());
+        store.lock().unwrap().pin("a");
+        fs::write(store.lock().unwrap().part_path("a"), part).unwrap();
+        let (url, request) = serve(response);
+        let job = Job {
+            ino: 2,
+            id: "a".to_string(),
+            url,
+            length: 8,
+        };
+        let (lengths, learned) = channel();
+        let connections = Connections::new(1, 1);
+        download(&Client::new(), &store, &connections, &lengths, &job).unwrap();
+        // The library had the length wrong.
+        assert_eq!(learned.try_iter().collect::<Vec<_>>(), vec![(2, 10)]);
+        let mut store = store.lock().unwrap();
+        assert!(store.is_complete("a"));
+        let data = store.read("a", Range { start: 0, end: 10 }).unwrap();
+        (data, request.join().unwrap())
+    }
+
+    #[test]
+    fn resume() {
+        let (data, request) = pin(
+            b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 4-9/10\r\n\
+            Content-Length: 6\r\n\r\n456789",
+            b"0123",
+        );
+        assert!(request.contains("bytes=4-"));
+        assert_eq!(data, b"0123456789");
+    }
+
+    #[test]
+    fn range_ignored() {
+        // The whole file comes again, the part is started over.
+        let (data, _) = pin(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabcdefghij",
+            b"0123",
+        );
+        assert_eq!(data, b"abcdefghij");
+    }
+}
@@ -0,0 +1,343 @@
+//! Virtual M3U playlists of the library: everything, one per album, artist
+//! and genre, and the playlists kept on the server. Their content is
+//! rendered on read.
+
+use crate::{template, Track};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+
+/// Tracks a playlist holds.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selection {
+    All,
+    // Album artist (artist if there is none) and album title.
+    Album(Option<String>, String),
+    Artist(String),
+    Genre(String),
+    // Track indexes of a server playlist, in its order.
+    Listed(Vec<usize>),
+}
+
+impl Selection {
+    pub fn matches(&self, track: &Track) -> bool {
+        let (field, value) = match self {
+            Selection::All => return true,
+            Selection::Listed(_) => return false,
+            Selection::Album(artist, album) => {
+                return track.album.as_ref() == Some(album)
+                    && album_artist(track) == artist.as_ref()
+            }
+            Selection::Artist(artist) => (&track.artist, artist),
+            Selection::Genre(genre) => (&track.genre, genre),
+        };
+        field.as_ref() == Some(value)
+    }
+
+    /// Indexes of the selected tracks. Listed tracks keep their order,
+    /// the others are ordered by their `paths`.
+    pub fn select(&self, tracks: &[Track], paths: &[String]) -> Vec<usize> {
+        if let Selection::Listed(listed) = self {
+            return listed.clone();
+        }
+        let mut selected: Vec<usize> = (0..tracks.len())
+            .filter(|&i| self.matches(&tracks[i]))
+            .collect();
+        // Albums in their own order, one after another.
+        selected.sort_by_key(|&i| {
+            let track = &tracks[i];
+            (
+                album_artist(track),
+                &track.album,
+                track.disc_number,
+                track.track_number,
+                &paths[i],
+            )
+        });
+        selected
+    }
+}
+
+/// Artist albums are told apart by, as in `layout::next_in_album`.
+fn album_artist(track: &Track) -> Option<&String> {
+    track.album_artist.as_ref().or(track.artist.as_ref())
+}
+
+/// Playlist kept on the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerPlaylist {
+    pub name: String,
+    pub ids: Vec<String>,
+}
+
+fn id_of(value: &Value) -> Option<String> {
+    match value {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        Value::Object(entry) => entry.get("id").and_then(id_of),
+        _ => None,
+    }
+}
+
+/// Read playlists as returned by the server. Tracks may be listed under
+/// `songs`, `tracks` or `entry`, as ids or objects with an `id`.
+pub fn parse_server_playlists(values: &[Value]) -> Vec<ServerPlaylist> {
+    let mut playlists = Vec::new();
+    for (i, value) in values.iter().enumerate() {
+        // Names become directories.
+        let name = ["name", "title"]
+            .iter()
+            .find_map(|key| value.get(key).and_then(id_of))
+            .map(|name| template::sanitize(name.trim()))
+            .filter(|name| !matches!(name.as_str(), "" | "." | ".."));
+        let entries = ["songs", "tracks", "entry"]
+            .iter()
+            .find_map(|key| value.get(key).and_then(Value::as_array));
+        match (name, entries) {
+            (Some(name), Some(entries)) => playlists.push(ServerPlaylist {
+                name,
+                ids: entries.iter().filter_map(id_of).collect(),
+            }),
+            _ => warn!("Skipping playlist #{} without name or tracks", i),
+        }
+    }
+    playlists
+}
+
+/// Turn server playlists into directory names and track indexes, dropping
+/// tracks which aren't in the library.
+pub fn resolve_server_playlists(
+    playlists: &[ServerPlaylist],
+    tracks: &[Track],
+) -> Vec<(String, Vec<usize>)> {
+    let by_id: HashMap<&str, usize> = tracks
+        .iter()
+        .enumerate()
+        .filter_map(|(i, track)| Some((track.id.as_deref()?, i)))
+        .collect();
+    let mut names = BTreeSet::new();
+    let mut resolved = Vec::new();
+    for playlist in playlists {
+        let base = playlist.name.clone();
+        let mut name = base.clone();
+        let mut n = 1;
+        while !names.insert(name.clone()) {
+            n += 1;
+            name = format!("{} ({})", base, n);
+        }
+        let listed = playlist
+            .ids
+            .iter()
+            .filter_map(|id| match by_id.get(id.as_str()) {
+                Some(&i) => Some(i),
+                None => {
+                    debug!("Playlist {} refers to unknown track {}", name, id);
+                    None
+                }
+            })
+            .collect();
+        resolved.push((name, listed));
+    }
+    resolved
+}
+
+/// Playlist file name for an album, artist or genre.
+fn file_name(name: &str) -> String {
+    format!("{}.m3u8", template::sanitize(name))
+}
+
+/// Playlist directory, the track field it groups by and the selection.
+type Group = (
+    &'static str,
+    fn(&Track) -> &Option<String>,
+    fn(String) -> Selection,
+);
+
+/// Add `selection` as `dir/file` unless the name is taken.
+fn add(
+    playlists: &mut Vec<(Option<&'static str>, String, Selection)>,
+    files: &mut BTreeSet<String>,
+    dir: &'static str,
+    file: String,
+    selection: Selection,
+) {
+    if files.insert(file.clone()) {
+        playlists.push((Some(dir), file, selection));
+    } else {
+        warn!(
+            "Playlist {}/{} already exists, skipping {:?}",
+            dir, file, selection
+        );
+    }
+}
+
+/// Every playlist of the library: subdirectory (`None` for the top one),
+/// file name and what it holds. Albums of the same title by different
+/// artists get the artist added to the name.
+pub fn playlists(tracks: &[Track]) -> Vec<(Option<&'static str>, String, Selection)> {
+    let mut playlists = vec![(None, "all.m3u8".to_string(), Selection::All)];
+    let albums: BTreeSet<(&String, Option<&String>)> = tracks
+        .iter()
+        .filter_map(|track| Some((track.album.as_ref()?, album_artist(track))))
+        .filter(|(album, _)| !album.is_empty())
+        .collect();
+    let mut titles: HashMap<&String, usize> = HashMap::new();
+    for (album, _) in &albums {
+        *titles.entry(album).or_default() += 1;
+    }
+    let mut files = BTreeSet::new();
+    for (album, artist) in albums {
+        let name = match artist {
+            Some(artist) if titles[album] > 1 => format!("{} ({})", album, artist),
+            _ => album.clone(),
+        };
+        let selection = Selection::Album(artist.cloned(), album.clone());
+        add(
+            &mut playlists,
+            &mut files,
+            "albums",
+            file_name(&name),
+            selection,
+        );
+    }
+    let groups: [Group; 2] = [
+        ("artists", |track| &track.artist, Selection::Artist),
+        ("genres", |track| &track.genre, Selection::Genre),
+    ];
+    for (dir, field, selection) in groups.iter() {
+        let names: BTreeSet<&String> = tracks
+            .iter()
+            .filter_map(|track| field(track).as_ref())
+            .filter(|name| !name.is_empty())
+            .collect();
+        let mut files = BTreeSet::new();
+        for name in names {
+            add(
+                &mut playlists,
+                &mut files,
+                dir,
+                file_name(name),
+                selection(name.clone()),
+            );
+        }
+    }
+    playlists
+}
+
+/// Extended M3U of `entries`: tracks with their paths relative to the playlist.
+pub fn render<'a>(entries: impl Iterator<Item = (&'a Track, String)>) -> String {
+    let mut content = String::from("#EXTM3U\n");
+    for (track, path) in entries {
+        let title = match (&track.artist, &track.name) {
+            (Some(artist), Some(name)) => format!("{} - {}", artist, name),
+            (None, Some(name)) => name.clone(),
+            _ => path.rsplit('/').next().unwrap_or_default().to_string(),
+        };
+        let duration = track
+            .duration
+            .map_or(-1, |duration| duration.round() as i64);
+        content.push_str(&format!("#EXTINF:{},{}\n{}\n", duration, title, path));
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(name: &str, artist: &str, album: &str) -> Track {
+        Track {
+            id: Some(name.to_string()),
+            name: Some(name.to_string()),
+            artist: Some(artist.to_string()),
+            album: Some(album.to_string()),
+            genre: None,
+            year: None,
+            format: None,
+            filetype: None,
+            path: Some(format!("/{}.mp3", name)),
+            size: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn playlists_and_content() {
+        let tracks = vec![
+            track("One", "Band", "AC/DC Covers"),
+            track("Two", "Band", "Live"),
+        ];
+        let mut more = tracks.clone();
+        more.push(track("Three", "Other\u{0}Band", "Live"));
+        more.push(track("Four", "Other\u{0}Band", "Bad\nName"));
+        let names: Vec<String> = playlists(&more)
+            .into_iter()
+            .map(|(dir, file, _)| format!("{}/{}", dir.unwrap_or("."), file))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "./all.m3u8",
+                "albums/AC_DC Covers.m3u8",
+                "albums/Bad_Name.m3u8",
+                "albums/Live (Band).m3u8",
+                "albums/Live (Other_Band).m3u8",
+                "artists/Band.m3u8",
+                "artists/Other_Band.m3u8"
+            ]
+        );
+        let live = Selection::Album(Some("Band".to_string()), "Live".to_string());
+        assert!(live.matches(&tracks[1]));
+        assert!(!live.matches(&more[2]));
+        assert!(!Selection::Genre("Rock".to_string()).matches(&tracks[1]));
+        let paths = vec!["b".to_string(), "a".to_string()];
+        let selection = Selection::Artist("Band".to_string());
+        assert_eq!(selection.select(&tracks, &paths), vec![0, 1]);
+        let mut same_album = tracks.clone();
+        same_album[0].album = same_album[1].album.clone();
+        assert_eq!(selection.select(&same_album, &paths), vec![1, 0]);
+        same_album[0].track_number = Some(1);
+        same_album[1].track_number = Some(2);
+        assert_eq!(selection.select(&same_album, &paths), vec![0, 1]);
+        assert_eq!(
+            Selection::Listed(vec![0, 0]).select(&tracks, &paths),
+            vec![0, 0]
+        );
+        let content = render(
+            tracks
+                .iter()
+                .map(|t| (t, format!("../{}.mp3", t.id.as_ref().unwrap()))),
+        );
+        assert_eq!(
+            content,
+            "#EXTM3U\n#EXTINF:-1,Band - One\n../One.mp3\n#EXTINF:-1,Band - Two\n../Two.mp3\n"
+        );
+    }
+
+    #[test]
+    fn server_playlists() {
+        let values: Vec<Value> = serde_json::from_str(
+            r#"[
+                {"name": "Road", "songs": ["Two", {"id": "One"}, "Gone"]},
+                {"title": "Road", "tracks": [{"id": "Two"}]},
+                {"name": "Broken"},
+                {"name": "..", "songs": ["One"]},
+                {"name": " . ", "songs": ["One"]},
+                {"name": "A/B", "songs": ["One"]}
+            ]"#,
+        )
+        .unwrap();
+        let playlists = parse_server_playlists(&values);
+        assert_eq!(playlists.len(), 3);
+        assert_eq!(playlists[2].name, "A_B");
+        let playlists = &playlists[..2];
+        assert_eq!(playlists[0].ids, vec!["Two", "One", "Gone"]);
+        let tracks = vec![track("One", "Band", "Live"), track("Two", "Band", "Live")];
+        assert_eq!(
+            resolve_server_playlists(playlists, &tracks),
+            vec![
+                ("Road".to_string(), vec![1, 0]),
+                ("Road (2)".to_string(), vec![1])
+            ]
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Background fetching of tracks which are likely to be played next.
+
+use crate::{
+    cache::BlockCache,
+    http::{self, Connections},
+    range::Range,
+    METRICS,
+};
+use reqwest::blocking::Client;
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        mpsc::{channel, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// Largest piece fetched with a single request.
+const PIECE: u64 = 4 * 1024 * 1024;
+
+/// How much of the next track to fetch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Prefetch {
+    Off,
+    Head,
+    Full,
+}
+
+impl FromStr for Prefetch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Prefetch::Off),
+            "head" => Ok(Prefetch::Head),
+            "full" => Ok(Prefetch::Full),
+            _ => Err(format!(
+                "Unknown prefetch mode '{}'. Use off, head or full.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Prefetch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Prefetch::Off => "off",
+            Prefetch::Head => "head",
+            Prefetch::Full => "full",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub struct Job {
+    pub ino: u64,
+    pub url: String,
+    pub length: u64,
+    pub range: Range,
+}
+
+/// Start the prefetch thread, filling `cache` with ranges of queued tracks.
+/// Lengths the server reports other than the job's go to `lengths`.
+pub fn spawn(
+    cache: Arc<Mutex<BlockCache>>,
+    connections: Arc<Connections>,
+    lengths: Sender<(u64, u64)>,
+) -> Sender<Job> {
+    let (sender, receiver) = channel::<Job>();
+    thread::Builder::new()
+        .name("prefetch".to_string())
+        .spawn(move || {
+            let client = Client::new();
+            for job in receiver {
+                let (missing, block_size) = {
+                    let cache = cache.lock().unwrap();
+                    (
+                        cache.missing(job.ino, job.length, job.range),
+                        cache.block_size(),
+                    )
+                };
+                // Keep pieces block aligned so every fetched block gets cached.
+                let piece_size = (PIECE / block_size).max(1) * block_size;
+                let mut learned = false;
+                for run in missing {
+                    let fetched = http::fetch_parallel(
+                        &client,
+                        &job.url,
+                        run,
+                        job.length,
+                        piece_size,
+                        &connections,
+                        |piece, fetched| {
+                            let length = fetched.total.unwrap_or(job.length);
+                            if length != job.length && !learned {
+                                learned = lengths.send((job.ino, length)).is_ok();
+                            }
+                            cache.lock().unwrap().insert(
+                                job.ino,
+                                length,
+                                piece.start,
+                                &fetched.data,
+                            );
+                            Ok(true)
+                        },
+                    );
+                    if let Err(e) = fetched {
+                        error!("An error prefetching {}. {}", job.url, e);
+                        METRICS.lock().unwrap().connect_errors += 1;
+                        break;
+                    }
+                }
+                debug!("{} - Prefetched {}", job.ino, job.url);
+            }
+        })
+        .expect("Couldn't start prefetch thread");
+    sender
+}
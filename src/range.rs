@@ -0,0 +1,145 @@
+//! Byte range arithmetic for `read`. Everything is `u64` so files above 4 GiB
+//! are served the same way as small ones.
+
+/// Half-open byte range `[start, end)` within a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Range {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Value for the HTTP `Range` header. HTTP ranges are inclusive.
+    pub fn header(&self) -> String {
+        format!("bytes={}-{}", self.start, self.end - 1)
+    }
+}
+
+/// Range to serve for a `read` of `size` bytes at `offset` from a file of
+/// `length` bytes. `None` means there's nothing to read: the offset is at or
+/// past the end of file, or the request is empty.
+pub fn read_range(offset: i64, size: u32, length: u64) -> Option<Range> {
+    if offset < 0 || size == 0 {
+        return None;
+    }
+    let start = offset as u64;
+    if start >= length {
+        return None;
+    }
+    let end = start.saturating_add(size as u64).min(length);
+    Some(Range { start, end })
+}
+
+/// Range of the file head kept in cache, or `None` for an empty file.
+pub fn head_range(length: u64, cache_head: u64) -> Option<Range> {
+    let end = length.min(cache_head);
+    if end == 0 {
+        return None;
+    }
+    Some(Range { start: 0, end })
+}
+
+/// Range of the file tail kept in cache, or `None` for an empty file.
+pub fn tail_range(length: u64, cache_tail: u64) -> Option<Range> {
+    let start = length.saturating_sub(cache_tail);
+    if start == length {
+        return None;
+    }
+    Some(Range { start, end: length })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const GIB: u64 = 1 << 30;
+
+    #[test]
+    fn boundaries() {
+        assert_eq!(read_range(0, 4096, 0), None);
+        assert_eq!(read_range(10, 4096, 10), None);
+        assert_eq!(read_range(11, 4096, 10), None);
+        assert_eq!(read_range(-1, 4096, 10), None);
+        assert_eq!(read_range(0, 0, 10), None);
+        assert_eq!(read_range(9, 4096, 10), Some(Range { start: 9, end: 10 }));
+        assert_eq!(read_range(0, 4096, 10).unwrap().header(), "bytes=0-9");
+        let big = 5 * GIB;
+        let range = read_range((big - 100) as i64, 4096, big).unwrap();
+        assert_eq!(range.len(), 100);
+        assert_eq!(range.header(), format!("bytes={}-{}", big - 100, big - 1));
+        let range = read_range((4 * GIB) as i64, 131072, big).unwrap();
+        assert_eq!(
+            range,
+            Range {
+                start: 4 * GIB,
+                end: 4 * GIB + 131072
+            }
+        );
+        assert_eq!(head_range(0, 768 * 1024), None);
+        assert_eq!(
+            head_range(10, 768 * 1024),
+            Some(Range { start: 0, end: 10 })
+        );
+        assert_eq!(head_range(big, 768 * 1024).unwrap().len(), 768 * 1024);
+        assert_eq!(tail_range(0, 128 * 1024), None);
+        assert_eq!(tail_range(10, 0), None);
+        assert_eq!(
+            tail_range(10, 128 * 1024),
+            Some(Range { start: 0, end: 10 })
+        );
+        assert_eq!(
+            tail_range(big, 1024),
+            Some(Range {
+                start: big - 1024,
+                end: big
+            })
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn read_range_stays_in_file(offset in any::<i64>(), size in any::<u32>(), length in any::<u64>()) {
+            match read_range(offset, size, length) {
+                Some(range) => {
+                    prop_assert!(offset >= 0);
+                    prop_assert_eq!(range.start, offset as u64);
+                    prop_assert!(range.start < range.end);
+                    prop_assert!(range.end <= length);
+                    prop_assert!(range.len() <= size as u64);
+                    // Short reads only happen at the end of file.
+                    prop_assert!(range.len() == size as u64 || range.end == length);
+                }
+                None => prop_assert!(offset < 0 || size == 0 || offset as u64 >= length),
+            }
+        }
+
+        #[test]
+        fn reads_near_eof_of_large_files(length in 4 * GIB..64 * GIB, back in 0u64..1 << 20, size in 1u32..1 << 20) {
+            let offset = length - back.min(length);
+            match read_range(offset as i64, size, length) {
+                Some(range) => {
+                    prop_assert_eq!(range.end, (offset + size as u64).min(length));
+                    prop_assert_eq!(range.header(), format!("bytes={}-{}", offset, range.end - 1));
+                }
+                None => prop_assert_eq!(offset, length),
+            }
+        }
+
+        #[test]
+        fn head_range_within_file(length in any::<u64>(), cache_head in any::<u64>()) {
+            match head_range(length, cache_head) {
+                Some(range) => {
+                    prop_assert_eq!(range.start, 0);
+                    prop_assert!(range.end <= length && range.end <= cache_head);
+                    prop_assert!(range.end == length || range.end == cache_head);
+                }
+                None => prop_assert!(length == 0 || cache_head == 0),
+            }
+        }
+    }
+}
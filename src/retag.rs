@@ -0,0 +1,357 @@
+//! Serving files with tags rebuilt from library metadata. The tag region at
+//! the beginning of a file is swapped for a new one, the rest is read from
+//! the server as is, shifted by the difference in size.
+
+use crate::{
+    range::Range,
+    tags::{self, id3v2_len, Block},
+    Track,
+};
+
+/// New tag replacing the first `end` bytes of a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Splice {
+    pub end: u64,
+    pub tag: Vec<u8>,
+    /// Where the original stops being served, the end of file if `None`.
+    pub stop: Option<u64>,
+}
+
+impl Splice {
+    /// Size of the file with the new tag.
+    pub fn size(&self, length: u64) -> u64 {
+        let stop = self.stop.map_or(length, |stop| stop.min(length));
+        stop.saturating_sub(self.end) + self.tag.len() as u64
+    }
+
+    /// Part of a read at `offset` served from the new tag, and the range of
+    /// the original file the rest of it comes from.
+    pub fn map(&self, offset: u64, size: u64) -> (Vec<u8>, Option<Range>) {
+        let tag_len = self.tag.len() as u64;
+        let end = offset + size;
+        let from_tag = self.tag[offset.min(tag_len) as usize..end.min(tag_len) as usize].to_vec();
+        let rest = if end > tag_len {
+            let start = offset.max(tag_len) - tag_len + self.end;
+            let rest_end = start + end - offset.max(tag_len);
+            let rest_end = self.stop.map_or(rest_end, |stop| rest_end.min(stop));
+            Some(Range {
+                start,
+                end: rest_end,
+            })
+            .filter(|rest| rest.start < rest.end)
+        } else {
+            None
+        };
+        (from_tag, rest)
+    }
+}
+
+/// Tag fields taken from the library: ID3v2 frame, Vorbis comment key, value.
+fn fields(track: &Track, version: u8) -> Vec<(&'static str, &'static str, String)> {
+    let year = if version == 3 { "TYER" } else { "TDRC" };
+    let fields = [
+        ("TIT2", "TITLE", track.name.clone()),
+        ("TPE1", "ARTIST", track.artist.clone()),
+        ("TALB", "ALBUM", track.album.clone()),
+        ("TPE2", "ALBUMARTIST", track.album_artist.clone()),
+        ("TCON", "GENRE", track.genre.clone()),
+        (year, "DATE", track.year.map(|year| year.to_string())),
+        (
+            "TRCK",
+            "TRACKNUMBER",
+            track.track_number.map(|n| n.to_string()),
+        ),
+        (
+            "TPOS",
+            "DISCNUMBER",
+            track.disc_number.map(|n| n.to_string()),
+        ),
+        (
+            "TLEN",
+            "",
+            track
+                .duration
+                .map(|d| ((d * 1000.0).round() as u64).to_string()),
+        ),
+    ];
+    fields
+        .iter()
+        .filter_map(|(frame, key, value)| {
+            Some((*frame, *key, value.clone().filter(|v| !v.is_empty())?))
+        })
+        .collect()
+}
+
+fn syncsafe_bytes(size: usize) -> [u8; 4] {
+    [
+        (size >> 21) as u8 & 0x7f,
+        (size >> 14) as u8 & 0x7f,
+        (size >> 7) as u8 & 0x7f,
+        size as u8 & 0x7f,
+    ]
+}
+
+/// ID3v2 tag of `track`, keeping frames of the `original` tag which the
+/// library has nothing for.
+fn id3v2(original: &[u8], track: &Track) -> Vec<u8> {
+    // Frames are copied as they are, so keep the version of the original.
+    // Unsynchronised tags are rare enough to be replaced as a whole.
+    let version = match original.get(3) {
+        Some(&version @ (3 | 4)) if original[5] & 0x80 == 0 => version,
+        _ => 4,
+    };
+    let fields = fields(track, version);
+    let mut body = Vec::new();
+    for (id, _, value) in &fields {
+        let text: Vec<u8> = match version {
+            // UTF-8 came with 2.4, 2.3 has UTF-16 with BOM.
+            3 => std::iter::once(1)
+                .chain([0xff, 0xfe])
+                .chain(value.encode_utf16().flat_map(u16::to_le_bytes))
+                .collect(),
+            _ => std::iter::once(3).chain(value.bytes()).collect(),
+        };
+        body.extend_from_slice(id.as_bytes());
+        match version {
+            3 => body.extend_from_slice(&(text.len() as u32).to_be_bytes()),
+            _ => body.extend_from_slice(&syncsafe_bytes(text.len())),
+        }
+        body.extend_from_slice(&[0, 0]);
+        body.extend(text);
+    }
+    if original.get(3) == Some(&version) {
+        for frame in tags::id3_frames(original) {
+            let id = frame.id;
+            let replaced = fields.iter().any(|(field, _, _)| field.as_bytes() == id)
+                || (id == b"TYER" || id == b"TDRC")
+                    && fields.iter().any(|(_, key, _)| *key == "DATE");
+            if !replaced {
+                body.extend_from_slice(frame.raw);
+            }
+        }
+    }
+    let mut tag = vec![b'I', b'D', b'3', version, 0, 0];
+    tag.extend_from_slice(&syncsafe_bytes(body.len()));
+    tag.extend(body);
+    tag
+}
+
+/// FLAC metadata with a Vorbis comment of `track`. Comments the library has
+/// nothing for are kept, padding is dropped.
+pub fn flac(blocks: &[Block], track: &Track) -> Vec<u8> {
+    let fields = fields(track, 4);
+    let mut vendor: &[u8] = b"mus-fuse";
+    let mut comments: Vec<Vec<u8>> = fields
+        .iter()
+        .filter(|(_, key, _)| !key.is_empty())
+        .map(|(_, key, value)| format!("{}={}", key, value).into_bytes())
+        .collect();
+    let original = blocks
+        .iter()
+        .filter(|(kind, _)| *kind == 4)
+        .find_map(|(_, block)| tags::vorbis_comments(block));
+    if let Some((original_vendor, original_comments)) = original {
+        vendor = original_vendor;
+        for comment in original_comments {
+            let key = comment.split(|&c| c == b'=').next().unwrap_or_default();
+            let key = String::from_utf8_lossy(key).to_ascii_uppercase();
+            if !fields.iter().any(|(_, field, _)| *field == key) {
+                comments.push(comment.to_vec());
+            }
+        }
+    }
+    let mut comment = (vendor.len() as u32).to_le_bytes().to_vec();
+    comment.extend_from_slice(vendor);
+    comment.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for entry in comments {
+        comment.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+        comment.extend(entry);
+    }
+    // STREAMINFO stays first.
+    let mut kept: Vec<Block> = blocks
+        .iter()
+        .filter(|(kind, _)| *kind != 1 && *kind != 4)
+        .copied()
+        .collect();
+    kept.push((4, &comment));
+    let mut metadata = b"fLaC".to_vec();
+    for (i, (kind, block)) in kept.iter().enumerate() {
+        let last = if i == kept.len() - 1 { 0x80 } else { 0 };
+        metadata.push(kind | last);
+        metadata.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+        metadata.extend_from_slice(block);
+    }
+    metadata
+}
+
+/// Splice putting tags of `track` into a file beginning with `head`.
+/// `Ok(None)` for formats which can't be retagged, `Err(n)` if the first
+/// `n` bytes are needed.
+pub fn splice(head: &[u8], track: &Track) -> Result<Option<Splice>, u64> {
+    let end = tags::region_end(head).map_err(|needed| needed as u64)?;
+    let id3_end = id3v2_len(head).unwrap_or(0);
+    let rest = &head[id3_end..];
+    if rest.starts_with(b"fLaC") {
+        // An ID3v2 tag in front of FLAC goes away.
+        return Ok(Some(Splice {
+            end: end as u64,
+            tag: flac(&tags::flac_blocks(rest).0, track),
+            stop: None,
+        }));
+    }
+    let mpeg = rest.len() >= 2 && rest[0] == 0xff && rest[1] & 0xe0 == 0xe0;
+    if id3_end > 0 || mpeg {
+        return Ok(Some(Splice {
+            end: end as u64,
+            tag: id3v2(&head[..id3_end], track),
+            stop: None,
+        }));
+    }
+    Ok(None)
+}
+
+/// Bytes at the end of a file read to find its trailing tags.
+pub const TAIL: u64 = 160;
+
+/// Length of the APEv2 and ID3v1 tags ending `tail`, the last `TAIL` bytes of
+/// a file. They'd go on showing the old metadata, so they aren't served.
+pub fn tail_tags_len(tail: &[u8]) -> u64 {
+    let mut len = 0;
+    if tail.len() >= 128 && tail[tail.len() - 128..].starts_with(b"TAG") {
+        len = 128;
+    }
+    let before = &tail[..tail.len() - len as usize];
+    if before.len() >= 32 {
+        let footer = &before[before.len() - 32..];
+        if footer.starts_with(b"APETAGEX") {
+            // Size covers items and footer, the header comes on top.
+            let header = if tags::le32(&footer[20..]) & (1 << 31) != 0 {
+                32
+            } else {
+                0
+            };
+            len += tags::le32(&footer[12..]) as u64 + header;
+        }
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::{
+        self,
+        tests::{id3, id3_frame, vorbis_comment},
+    };
+
+    fn track() -> Track {
+        Track {
+            name: Some("Jóga".to_string()),
+            artist: Some("Björk".to_string()),
+            track_number: Some(3),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn mp3() {
+        let mut file = id3(&[
+            id3_frame(b"TPE1", "Bjork"),
+            id3_frame(b"TCON", "Electronic"),
+        ]);
+        let end = file.len() as u64;
+        file.extend_from_slice(&[0xff, 0xfb, 0x90, 0x00]);
+        assert_eq!(splice(&file[..20], &track()), Err(end));
+        let splice = splice(&file, &track()).unwrap().unwrap();
+        assert_eq!(splice.end, end);
+        assert_eq!(splice.tag[3], 3);
+        let tags = tags::parse(&splice.tag).unwrap();
+        assert_eq!(tags.artist.as_deref(), Some("Björk"));
+        assert_eq!(tags.title.as_deref(), Some("Jóga"));
+        assert_eq!(tags.genre.as_deref(), Some("Electronic"));
+        assert_eq!(tags.track_number, Some(3));
+        assert_eq!(splice.size(file.len() as u64), splice.tag.len() as u64 + 4);
+        // Plain MPEG gets a new tag, unknown formats are left alone.
+        let plain = splice_of(&[0xff, 0xfb, 0x90, 0x00]);
+        assert_eq!(plain.end, 0);
+        assert_eq!(plain.tag[3], 4);
+        assert_eq!(super::splice(b"RIFF\0\0\0\0WAVE", &track()), Ok(None));
+    }
+
+    fn splice_of(head: &[u8]) -> Splice {
+        splice(head, &track()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn flac() {
+        let mut file = b"fLaC\x00\x00\x00\x22".to_vec();
+        file.extend_from_slice(&[0; 34]);
+        let comment = vorbis_comment(&["ARTIST=Bjork", "COMMENT=keep"]);
+        file.extend_from_slice(&(0x0400_0000u32 | comment.len() as u32).to_be_bytes());
+        file.extend(comment);
+        file.extend_from_slice(&[0x81, 0, 0, 16]);
+        file.extend_from_slice(&[0; 16]);
+        let end = file.len() as u64;
+        file.extend_from_slice(&[0xff, 0xf8]);
+        assert_eq!(splice(&file[..50], &track()), Err(end - 20));
+        let splice = splice_of(&file);
+        assert_eq!(splice.end, end);
+        let tags = tags::parse(&splice.tag).unwrap();
+        assert_eq!(tags.artist.as_deref(), Some("Björk"));
+        assert_eq!(tags.track_number, Some(3));
+        let text = String::from_utf8_lossy(&splice.tag);
+        assert!(text.contains("COMMENT=keep") && !text.contains("Bjork"));
+    }
+
+    #[test]
+    fn mapping() {
+        let splice = Splice {
+            end: 10,
+            tag: b"abcd".to_vec(),
+            stop: None,
+        };
+        assert_eq!(splice.size(100), 94);
+        assert_eq!(splice.map(0, 2), (b"ab".to_vec(), None));
+        assert_eq!(
+            splice.map(2, 4),
+            (b"cd".to_vec(), Some(Range { start: 10, end: 12 }))
+        );
+        assert_eq!(
+            splice.map(6, 4),
+            (Vec::new(), Some(Range { start: 12, end: 16 }))
+        );
+        // A part of the original, as for tracks of a CUE sheet.
+        let part = Splice {
+            stop: Some(20),
+            ..splice
+        };
+        assert_eq!(part.size(100), 14);
+        assert_eq!(
+            part.map(12, 10),
+            (Vec::new(), Some(Range { start: 18, end: 20 }))
+        );
+        assert_eq!(part.map(16, 4), (Vec::new(), None));
+    }
+
+    #[test]
+    fn tail_tags() {
+        let mut file = vec![0xff; 200];
+        assert_eq!(tail_tags_len(&file), 0);
+        // APEv2 with a header: 32 + 8 bytes of items + 32 of footer.
+        file.extend_from_slice(b"APETAGEX");
+        file.extend_from_slice(&[0; 32]);
+        file.extend_from_slice(b"APETAGEX");
+        file.extend_from_slice(&2000u32.to_le_bytes());
+        file.extend_from_slice(&40u32.to_le_bytes());
+        file.extend_from_slice(&1u32.to_le_bytes());
+        file.extend_from_slice(&(1u32 << 31).to_le_bytes());
+        file.extend_from_slice(&[0; 8]);
+        let tail = |file: &[u8]| file[file.len() - TAIL as usize..].to_vec();
+        assert_eq!(tail_tags_len(&tail(&file)), 72);
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, b' ');
+        file.extend_from_slice(&id3v1);
+        assert_eq!(tail_tags_len(&tail(&file)), 200);
+        assert_eq!(tail_tags_len(&id3v1), 128);
+    }
+}
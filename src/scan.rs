@@ -0,0 +1,81 @@
+//! Background scanning of embedded tags in the cached beginning of files.
+//! Results are kept in `tags.json` of the cache directory, so they fill
+//! in missing metadata from the start on the next mount.
+
+use crate::tags::{self, Tags};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+const TAGS_FILE: &str = "tags.json";
+
+pub struct Job {
+    pub ino: u64,
+    pub id: String,
+    pub head: Vec<u8>,
+}
+
+/// Tags found by previous runs, by track id.
+pub fn load(dir: &Path) -> HashMap<String, Tags> {
+    let path = dir.join(TAGS_FILE);
+    match fs::read(&path) {
+        Ok(data) => match serde_json::from_slice(&data) {
+            Ok(tags) => tags,
+            Err(e) => {
+                warn!("Ignoring broken {}. {}", path.display(), e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn save(dir: &Path, tags: &HashMap<String, Tags>) -> io::Result<()> {
+    let temp = dir.join(format!("{}.tmp", TAGS_FILE));
+    fs::write(&temp, serde_json::to_vec(tags)?)?;
+    fs::rename(temp, dir.join(TAGS_FILE))
+}
+
+/// Start the scanner thread. It parses heads of queued tracks and sends back
+/// what it found, saving results to `dir` if there is one.
+pub fn spawn(
+    dir: Option<PathBuf>,
+    known: HashMap<String, Tags>,
+) -> (Sender<Job>, Receiver<(u64, Tags)>) {
+    let (sender, jobs) = channel::<Job>();
+    let (found, results) = channel();
+    thread::Builder::new()
+        .name("scan".to_string())
+        .spawn(move || {
+            let mut known = known;
+            while let Ok(job) = jobs.recv() {
+                let mut changed = false;
+                // Save once per batch of queued heads.
+                for job in std::iter::once(job).chain(jobs.try_iter()) {
+                    let tags = match tags::parse(&job.head) {
+                        Some(tags) => tags,
+                        None => {
+                            debug!("{} - No tags found", job.ino);
+                            continue;
+                        }
+                    };
+                    debug!("{} - Found tags {:?}", job.ino, tags);
+                    if found.send((job.ino, tags.clone())).is_err() {
+                        return;
+                    }
+                    changed |= known.insert(job.id, tags.clone()).as_ref() != Some(&tags);
+                }
+                if let (true, Some(dir)) = (changed, &dir) {
+                    if let Err(e) = save(dir, &known) {
+                        error!("Can't save tags to {}. {}", dir.display(), e);
+                    }
+                }
+            }
+        })
+        .unwrap();
+    (sender, results)
+}
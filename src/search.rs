@@ -0,0 +1,48 @@
+//! Tracks matching a query typed as a directory name under `search/`.
+
+use crate::{text, Track};
+
+/// Words of a query, folded for matching.
+pub fn words(query: &str) -> Vec<String> {
+    text::fold(query)
+        .split_whitespace()
+        .map(|word| word.to_string())
+        .collect()
+}
+
+/// Whether every word is found in the name, artist or album of `track`,
+/// ignoring case and diacritics.
+pub fn matches(words: &[String], track: &Track) -> bool {
+    let fields: Vec<&str> = [&track.name, &track.artist, &track.album]
+        .iter()
+        .filter_map(|field| field.as_deref())
+        .collect();
+    let haystack = text::fold(&fields.join(" "));
+    words.iter().all(|word| haystack.contains(word.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn case_and_diacritics() {
+        let track = Track {
+            id: Some("1".to_string()),
+            name: Some("Jóga".to_string()),
+            artist: Some("Björk".to_string()),
+            album: Some("Homogenic".to_string()),
+            genre: None,
+            year: None,
+            format: None,
+            filetype: None,
+            path: Some("/joga.flac".to_string()),
+            size: Some(1),
+            ..Default::default()
+        };
+        assert!(matches(&words("bjork"), &track));
+        assert!(matches(&words("JOGA  björk"), &track));
+        assert!(matches(&words("Bjo\u{308}rk"), &track));
+        assert!(!matches(&words("bjork debut"), &track));
+    }
+}
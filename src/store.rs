@@ -0,0 +1,407 @@
+//! Persistent on-disk cache of whole files. Pinned files are kept here for
+//! offline use and never evicted; other complete files go away least recently
+//! used first once the size budget is exceeded. Album covers are kept too,
+//! within a budget of their own.
+
+use crate::{range::Range, util};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+const PINS_FILE: &str = "pins";
+const FILES_DIR: &str = "files";
+const COVERS_DIR: &str = "covers";
+const PART_SUFFIX: &str = ".part";
+
+fn key(id: &str) -> String {
+    format!("{:016x}", util::hash(id))
+}
+
+/// Free space available to unprivileged users on the disk holding `dir`.
+fn disk_free(dir: &Path) -> Option<u64> {
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Progress {
+    pub downloaded: u64,
+    pub length: u64,
+    pub complete: bool,
+}
+
+#[derive(Debug, Default)]
+struct StoredFile {
+    progress: Progress,
+    // Logical clock of the last access, for eviction.
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct StoredCover {
+    size: u64,
+    used: u64,
+}
+
+pub struct Store {
+    dir: PathBuf,
+    max_bytes: u64,
+    covers_max_bytes: u64,
+    covers: HashMap<String, StoredCover>,
+    // Pinned with setxattr, saved to disk.
+    pins: HashSet<String>,
+    // Pinned in config file, not saved.
+    config_pins: HashSet<String>,
+    files: HashMap<String, StoredFile>,
+    clock: u64,
+}
+
+impl Store {
+    /// Open (creating if needed) a store at `dir` and pick up files left from
+    /// previous runs.
+    pub fn open(dir: &Path, max_bytes: u64, covers_max_bytes: u64) -> io::Result<Store> {
+        fs::create_dir_all(dir.join(FILES_DIR))?;
+        fs::create_dir_all(dir.join(COVERS_DIR))?;
+        let mut covers = HashMap::new();
+        for entry in fs::read_dir(dir.join(COVERS_DIR))? {
+            let entry = entry?;
+            let size = entry.metadata()?.len();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            covers.insert(name, StoredCover { size, used: 0 });
+        }
+        let mut files = HashMap::new();
+        for entry in fs::read_dir(dir.join(FILES_DIR))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let size = entry.metadata()?.len();
+            let (name, complete) = match name.strip_suffix(PART_SUFFIX) {
+                Some(name) => (name.to_string(), false),
+                None => (name, true),
+            };
+            let stored: &mut StoredFile = files.entry(name).or_default();
+            if complete || !stored.progress.complete {
+                stored.progress = Progress {
+                    downloaded: size,
+                    length: if complete { size } else { 0 },
+                    complete,
+                };
+            }
+        }
+        let pins = match fs::read_to_string(dir.join(PINS_FILE)) {
+            Ok(pins) => pins
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(|line| line.to_string())
+                .collect(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Store {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            covers_max_bytes,
+            covers,
+            pins,
+            config_pins: HashSet::new(),
+            files,
+            clock: 0,
+        })
+    }
+
+    fn save_pins(&self) {
+        let mut pins: Vec<&String> = self.pins.iter().collect();
+        pins.sort();
+        let mut content = String::new();
+        for pin in pins {
+            content.push_str(pin);
+            content.push('\n');
+        }
+        if let Err(e) = fs::write(self.dir.join(PINS_FILE), content) {
+            error!("Can't save pin list to {}. {}", self.dir.display(), e);
+        }
+    }
+
+    pub fn is_pinned(&self, id: &str) -> bool {
+        self.pins.contains(id) || self.config_pins.contains(id)
+    }
+
+    /// Pin a track. Returns whether it wasn't pinned before.
+    pub fn pin(&mut self, id: &str) -> bool {
+        let new = !self.is_pinned(id);
+        if self.pins.insert(id.to_string()) {
+            self.save_pins();
+        }
+        new
+    }
+
+    pub fn pin_from_config(&mut self, id: &str) -> bool {
+        let new = !self.is_pinned(id);
+        self.config_pins.insert(id.to_string());
+        new
+    }
+
+    pub fn unpin(&mut self, id: &str) {
+        self.config_pins.remove(id);
+        if self.pins.remove(id) {
+            self.save_pins();
+        }
+        self.evict();
+    }
+
+    pub fn progress(&self, id: &str) -> Progress {
+        self.files
+            .get(&key(id))
+            .map(|stored| stored.progress)
+            .unwrap_or_default()
+    }
+
+    pub fn is_complete(&self, id: &str) -> bool {
+        self.progress(id).complete
+    }
+
+    /// Partially downloaded file of a track.
+    pub fn part_path(&self, id: &str) -> PathBuf {
+        self.dir
+            .join(FILES_DIR)
+            .join(format!("{}{}", key(id), PART_SUFFIX))
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(FILES_DIR).join(key(id))
+    }
+
+    pub fn set_progress(&mut self, id: &str, downloaded: u64, length: u64) {
+        let stored = self.files.entry(key(id)).or_default();
+        stored.progress = Progress {
+            downloaded,
+            length,
+            complete: false,
+        };
+    }
+
+    /// Move a fully downloaded file in place.
+    pub fn finish(&mut self, id: &str) -> io::Result<()> {
+        let path = self.path(id);
+        fs::rename(self.part_path(id), &path)?;
+        let length = fs::metadata(&path)?.len();
+        self.clock += 1;
+        let stored = self.files.entry(key(id)).or_default();
+        stored.progress = Progress {
+            downloaded: length,
+            length,
+            complete: true,
+        };
+        stored.used = self.clock;
+        self.evict();
+        Ok(())
+    }
+
+    /// Read `range` of a complete file.
+    pub fn read(&mut self, id: &str, range: Range) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.path(id))?;
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut chunk = Vec::with_capacity(range.len() as usize);
+        file.take(range.len()).read_to_end(&mut chunk)?;
+        self.clock += 1;
+        if let Some(stored) = self.files.get_mut(&key(id)) {
+            stored.used = self.clock;
+        }
+        Ok(chunk)
+    }
+
+    /// Cover art saved for `id`.
+    pub fn cover(&mut self, id: &str) -> Option<Vec<u8>> {
+        let name = key(id);
+        let stored = self.covers.get_mut(&name)?;
+        self.clock += 1;
+        stored.used = self.clock;
+        fs::read(self.dir.join(COVERS_DIR).join(name)).ok()
+    }
+
+    pub fn save_cover(&mut self, id: &str, image: &[u8]) -> io::Result<()> {
+        let name = key(id);
+        fs::write(self.dir.join(COVERS_DIR).join(&name), image)?;
+        self.clock += 1;
+        self.covers.insert(
+            name,
+            StoredCover {
+                size: image.len() as u64,
+                used: self.clock,
+            },
+        );
+        // Least recently used covers go first.
+        while self.covers.values().map(|cover| cover.size).sum::<u64>() > self.covers_max_bytes {
+            let name = match self.covers.iter().min_by_key(|(_, cover)| cover.used) {
+                Some((name, _)) => name.clone(),
+                None => break,
+            };
+            self.covers.remove(&name);
+            let path = self.dir.join(COVERS_DIR).join(name);
+            debug!("Disk cache dropped {}", path.display());
+            if let Err(e) = fs::remove_file(&path) {
+                error!("Can't remove {}. {}", path.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes of disk used by the store.
+    pub fn usage(&self) -> u64 {
+        self.files
+            .values()
+            .map(|stored| stored.progress.downloaded)
+            .sum()
+    }
+
+    /// Bytes which can still be stored: the rest of the budget, but no more
+    /// than the disk has free.
+    pub fn free(&self) -> u64 {
+        let budget = self.max_bytes.saturating_sub(self.usage());
+        match disk_free(&self.dir) {
+            Some(disk) => budget.min(disk),
+            None => budget,
+        }
+    }
+
+    /// Drop files which aren't pinned until the store fits into its budget:
+    /// downloads left over from unpinned tracks first, then complete files
+    /// least recently used first.
+    fn evict(&mut self) {
+        let pinned: HashSet<String> = self
+            .pins
+            .iter()
+            .chain(self.config_pins.iter())
+            .map(|id| key(id))
+            .collect();
+        let mut usage = self.usage();
+        while usage > self.max_bytes {
+            let victim = self
+                .files
+                .iter()
+                .filter(|(name, _)| !pinned.contains(*name))
+                .min_by_key(|(_, stored)| (stored.progress.complete, stored.used))
+                .map(|(name, _)| name.clone());
+            let name = match victim {
+                Some(name) => name,
+                None => {
+                    warn!(
+                        "Pinned files take {} bytes, more than disk cache size {}.",
+                        usage, self.max_bytes
+                    );
+                    break;
+                }
+            };
+            let stored = self.files.remove(&name).unwrap();
+            usage -= stored.progress.downloaded;
+            let path = self.dir.join(FILES_DIR).join(if stored.progress.complete {
+                name.clone()
+            } else {
+                format!("{}{}", name, PART_SUFFIX)
+            });
+            debug!("Disk cache dropped {}", path.display());
+            if let Err(e) = fs::remove_file(&path) {
+                error!("Can't remove {}. {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Complete file of `id` holding `size` bytes.
+    fn add(store: &mut Store, id: &str, size: usize) {
+        fs::write(store.part_path(id), vec![0; size]).unwrap();
+        store.finish(id).unwrap();
+    }
+
+    #[test]
+    fn pins_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(dir.path(), 100, 100).unwrap();
+        assert!(store.pin("a"));
+        assert!(!store.pin("a"));
+        assert!(store.pin_from_config("b"));
+        store.pin("c");
+        store.unpin("c");
+        let store = Store::open(dir.path(), 100, 100).unwrap();
+        assert!(store.is_pinned("a"));
+        // Config pins come from the config file on every mount.
+        assert!(!store.is_pinned("b"));
+        assert!(!store.is_pinned("c"));
+    }
+
+    #[test]
+    fn files_left_from_previous_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(dir.path(), 100, 100).unwrap();
+        add(&mut store, "done", 10);
+        fs::write(store.part_path("half"), [0; 4]).unwrap();
+        let store = Store::open(dir.path(), 100, 100).unwrap();
+        let done = Progress {
+            downloaded: 10,
+            length: 10,
+            complete: true,
+        };
+        assert_eq!(store.progress("done"), done);
+        // Downloads go on from where they stopped.
+        let half = Progress {
+            downloaded: 4,
+            length: 0,
+            complete: false,
+        };
+        assert_eq!(store.progress("half"), half);
+        assert_eq!(store.usage(), 14);
+    }
+
+    #[test]
+    fn eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(dir.path(), 25, 100).unwrap();
+        store.pin("pinned");
+        add(&mut store, "pinned", 10);
+        add(&mut store, "old", 10);
+        fs::write(store.part_path("unpinned"), [0; 2]).unwrap();
+        store.set_progress("unpinned", 2, 10);
+        add(&mut store, "new", 4);
+        // The leftover download goes first.
+        assert_eq!(store.progress("unpinned"), Progress::default());
+        assert!(!store.part_path("unpinned").exists());
+        assert!(store.is_complete("old"));
+        store.read("old", Range { start: 0, end: 1 }).unwrap();
+        add(&mut store, "newer", 5);
+        // Least recently used unpinned file goes next, the pinned one stays.
+        assert!(!store.is_complete("new"));
+        assert!(store.is_complete("old"));
+        assert!(store.is_complete("pinned"));
+        assert_eq!(store.usage(), 25);
+        store.unpin("pinned");
+        add(&mut store, "last", 1);
+        assert!(!store.is_complete("pinned"));
+        assert_eq!(store.usage(), 16);
+    }
+
+    #[test]
+    fn cover_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(dir.path(), 100, 10).unwrap();
+        store.save_cover("a", &[1; 4]).unwrap();
+        store.save_cover("b", &[2; 4]).unwrap();
+        assert_eq!(store.cover("a"), Some(vec![1; 4]));
+        store.save_cover("c", &[3; 4]).unwrap();
+        assert_eq!(store.cover("b"), None);
+        let mut store = Store::open(dir.path(), 100, 10).unwrap();
+        assert_eq!(store.cover("a"), Some(vec![1; 4]));
+        assert_eq!(store.cover("c"), Some(vec![3; 4]));
+    }
+}
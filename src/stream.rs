@@ -0,0 +1,155 @@
+//! Sequential reading of a file over one long GET.
+
+use crate::{http, limit, HTTP_AUTH, METRICS};
+use reqwest::{
+    blocking::{Client, Response},
+    header::{CONTENT_RANGE, RANGE},
+    StatusCode,
+};
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Read},
+};
+
+/// Bytes read from the body at once, and accounted to the rate limit.
+const CHUNK: usize = 64 * 1024;
+/// Most bytes skipped when the server sends the whole file instead of a range.
+const MAX_SKIP: u64 = 16 * CHUNK as u64;
+
+/// The server ignored the Range header, and skipping to the position would
+/// download too much.
+#[derive(Debug)]
+pub struct RangeIgnored;
+
+impl fmt::Display for RangeIgnored {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Server ignores Range")
+    }
+}
+
+impl Error for RangeIgnored {}
+
+/// An open response body positioned at `position` of the file.
+pub struct Stream {
+    response: Response,
+    position: u64,
+    /// Complete file length, if the server told it.
+    pub total: Option<u64>,
+}
+
+impl Stream {
+    /// GET `url` from byte `start` to the end of file. Fails with
+    /// `RangeIgnored` rather than skip more than `MAX_SKIP` bytes.
+    pub fn open(client: &Client, url: &str, start: u64) -> Result<Stream, Box<dyn Error>> {
+        if let Some(quota) = limit::exhausted() {
+            return Err(format!("The {} quota is exhausted", quota).into());
+        }
+        let response;
+        unsafe {
+            response = client
+                .get(url)
+                .header(RANGE, format!("bytes={}-", start))
+                .header("Authorization", format!("Basic {}", HTTP_AUTH))
+                .send()?;
+        }
+        {
+            let mut metrics = METRICS.lock().unwrap();
+            metrics.http_requests += 1;
+            metrics.streams_opened += 1;
+        }
+        let total = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|header| header.to_str().ok())
+            .and_then(http::content_range_total);
+        let ignored_range = response.status() == StatusCode::OK;
+        if ignored_range && start > MAX_SKIP {
+            return Err(RangeIgnored.into());
+        }
+        let mut stream = Stream {
+            response: response.error_for_status()?,
+            position: 0,
+            total,
+        };
+        if ignored_range {
+            // Whole file came back, skip to the wanted position.
+            let skipped = io::copy(&mut (&mut stream.response).take(start), &mut io::sink())?;
+            limit::consumed(skipped);
+            stream.position = skipped;
+        } else {
+            stream.position = start;
+        }
+        Ok(stream)
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Next `len` bytes of the file. Shorter at the end of file.
+    pub fn read(&mut self, len: u64) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len as usize);
+        let mut body = (&mut self.response).take(len);
+        let mut buffer = vec![0; CHUNK.min(len as usize)];
+        loop {
+            let n = body.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&buffer[..n]);
+            self.position += n as u64;
+            limit::consumed(n as u64);
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, net::TcpListener, thread};
+
+    /// URL of a server answering one request with `response`.
+    fn serve(response: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/track.mp3", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut byte = [0];
+            while !request.ends_with(b"\r\n\r\n") && socket.read(&mut byte).unwrap() == 1 {
+                request.push(byte[0]);
+            }
+            socket.write_all(response).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn ranged() {
+        let url = serve(
+            b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 4-9/10\r\n\
+            Content-Length: 6\r\n\r\n456789",
+        );
+        let mut stream = Stream::open(&Client::new(), &url, 4).unwrap();
+        assert_eq!((stream.position(), stream.total), (4, Some(10)));
+        assert_eq!(stream.read(4).unwrap(), b"4567");
+        assert_eq!(stream.read(4).unwrap(), b"89");
+        assert_eq!(stream.position(), 10);
+    }
+
+    #[test]
+    fn whole_file() {
+        let url = serve(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789");
+        let mut stream = Stream::open(&Client::new(), &url, 4).unwrap();
+        assert_eq!(stream.position(), 4);
+        assert_eq!(stream.read(3).unwrap(), b"456");
+        // Too far to skip to.
+        let url = serve(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789");
+        let err = Stream::open(&Client::new(), &url, MAX_SKIP + 1)
+            .err()
+            .unwrap();
+        assert!(err.is::<RangeIgnored>());
+    }
+}
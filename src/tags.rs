@@ -0,0 +1,723 @@
+//! Readers of embedded tags: ID3v2, FLAC and Ogg Vorbis comments, MP4 `ilst`.
+//! They work on the beginning of a file and give up quietly on anything
+//! cut short or malformed.
+
+use crate::Track;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+/// Metadata found in a file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub duration: Option<f64>,
+}
+
+impl Tags {
+    fn is_empty(&self) -> bool {
+        *self == Tags::default()
+    }
+
+    /// Set a field from a textual tag value, keeping the first value seen.
+    fn set(&mut self, key: &str, value: &str) {
+        let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+        if value.is_empty() {
+            return;
+        }
+        let text = || Some(value.to_string());
+        // `3/12` -> 3, `2020-04-27` -> 2020
+        let number = || {
+            let digits: String = value.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse::<u32>().ok()
+        };
+        match key {
+            "title" if self.title.is_none() => self.title = text(),
+            "artist" if self.artist.is_none() => self.artist = text(),
+            "album" if self.album.is_none() => self.album = text(),
+            "album_artist" if self.album_artist.is_none() => self.album_artist = text(),
+            "genre" if self.genre.is_none() => self.genre = text(),
+            "year" if self.year.is_none() => self.year = number().map(|year| year as i32),
+            "track" if self.track_number.is_none() => self.track_number = number(),
+            "disc" if self.disc_number.is_none() => self.disc_number = number(),
+            _ => (),
+        }
+    }
+
+    /// Fill fields of `track` which the library left empty.
+    pub fn fill(&self, track: &mut Track) {
+        fn missing<T: Clone>(field: &mut Option<T>, value: &Option<T>) {
+            if field.is_none() {
+                *field = value.clone();
+            }
+        }
+        missing(&mut track.name, &self.title);
+        missing(&mut track.artist, &self.artist);
+        missing(&mut track.album, &self.album);
+        missing(&mut track.album_artist, &self.album_artist);
+        missing(&mut track.genre, &self.genre);
+        missing(&mut track.year, &self.year);
+        missing(&mut track.track_number, &self.track_number);
+        missing(&mut track.disc_number, &self.disc_number);
+        missing(&mut track.duration, &self.duration);
+    }
+}
+
+pub fn be32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+}
+
+pub fn le32(data: &[u8]) -> u32 {
+    u32::from_le_bytes(data[..4].try_into().unwrap())
+}
+
+pub fn syncsafe(data: &[u8]) -> usize {
+    data[..4]
+        .iter()
+        .fold(0, |size, &byte| (size << 7) | (byte & 0x7f) as usize)
+}
+
+/// Length of the ID3v2 tag at the start of `data`, header and footer included.
+pub fn id3v2_len(data: &[u8]) -> Option<usize> {
+    if data.len() < 10 || &data[..3] != b"ID3" {
+        return None;
+    }
+    let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    Some(10 + syncsafe(&data[6..10]) + footer)
+}
+
+/// Text in one of the ID3v2 encodings.
+fn decode(encoding: u8, text: &[u8]) -> Option<String> {
+    let text = match encoding {
+        0 => text.iter().map(|&byte| byte as char).collect(),
+        1 | 2 => {
+            let mut units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            match units.first() {
+                Some(0xfffe) => {
+                    units.remove(0);
+                    units = units.into_iter().map(u16::swap_bytes).collect();
+                }
+                Some(0xfeff) => {
+                    units.remove(0);
+                }
+                _ if encoding == 1 => {
+                    // Without BOM little endian is the common guess.
+                    units = units.into_iter().map(u16::swap_bytes).collect();
+                }
+                _ => (),
+            }
+            String::from_utf16_lossy(&units)
+        }
+        3 => String::from_utf8_lossy(text).into_owned(),
+        _ => return None,
+    };
+    Some(text)
+}
+
+/// Text of an ID3v2 text frame, first value only.
+fn id3_text(data: &[u8]) -> Option<String> {
+    let (&encoding, text) = data.split_first()?;
+    let text = decode(encoding, text)?;
+    Some(text.split('\0').next().unwrap_or_default().to_string())
+}
+
+/// ID3v2 frame: its id, body and the whole frame with header.
+pub struct Frame<'a> {
+    pub id: &'a [u8],
+    pub body: &'a [u8],
+    pub raw: &'a [u8],
+}
+
+/// Frames of the ID3v2 tag at the start of `data`, as many as it holds whole.
+pub fn id3_frames(data: &[u8]) -> Vec<Frame<'_>> {
+    let mut frames = Vec::new();
+    let len = match id3v2_len(data) {
+        Some(len) => len.min(data.len()),
+        None => return frames,
+    };
+    let version = data[3];
+    let (id_len, header_len) = if version == 2 { (3, 6) } else { (4, 10) };
+    let mut pos = 10;
+    if data[5] & 0x40 != 0 && version > 2 && len >= 14 {
+        // Extended header
+        pos += match version {
+            3 => be32(&data[10..]) as usize + 4,
+            _ => syncsafe(&data[10..]),
+        };
+    }
+    while pos + header_len <= len {
+        let id = &data[pos..pos + id_len];
+        if id[0] == 0 {
+            break;
+        }
+        let size = match version {
+            2 => (be32(&data[pos + 2..]) & 0x00ff_ffff) as usize,
+            3 => be32(&data[pos + 4..]) as usize,
+            _ => syncsafe(&data[pos + 4..]),
+        };
+        let body = pos + header_len;
+        if body + size > len {
+            break;
+        }
+        frames.push(Frame {
+            id,
+            body: &data[body..body + size],
+            raw: &data[pos..body + size],
+        });
+        pos = body + size;
+    }
+    frames
+}
+
+fn parse_id3v2(data: &[u8], tags: &mut Tags) {
+    for frame in id3_frames(data) {
+        let key = match frame.id {
+            b"TIT2" | b"TT2" => "title",
+            b"TPE1" | b"TP1" => "artist",
+            b"TALB" | b"TAL" => "album",
+            b"TPE2" | b"TP2" => "album_artist",
+            b"TCON" | b"TCO" => "genre",
+            b"TYER" | b"TDRC" | b"TYE" => "year",
+            b"TRCK" | b"TRK" => "track",
+            b"TPOS" | b"TPA" => "disc",
+            b"TLEN" | b"TLE" => "length",
+            _ => continue,
+        };
+        let text = match id3_text(frame.body) {
+            Some(text) => text,
+            None => continue,
+        };
+        if key == "length" {
+            if let Ok(millis) = text.trim().parse::<f64>() {
+                tags.duration = tags.duration.or(Some(millis / 1000.0));
+            }
+        } else {
+            tags.set(key, &text);
+        }
+    }
+}
+
+/// Vendor and `KEY=value` entries of a Vorbis comment block, as many as it
+/// holds whole.
+pub fn vorbis_comments(data: &[u8]) -> Option<(&[u8], Vec<&[u8]>)> {
+    let mut pos = 0;
+    let mut next = |len: usize| {
+        let piece = data.get(pos..pos + len)?;
+        pos += len;
+        Some(piece)
+    };
+    let vendor = next(4).map(le32)? as usize;
+    let vendor = next(vendor)?;
+    let mut comments = Vec::new();
+    let count = next(4).map_or(0, le32);
+    for _ in 0..count {
+        match next(4).map(le32).and_then(|len| next(len as usize)) {
+            Some(comment) => comments.push(comment),
+            None => break,
+        }
+    }
+    Some((vendor, comments))
+}
+
+fn parse_vorbis_comment(data: &[u8], tags: &mut Tags) {
+    let comments = vorbis_comments(data).map(|(_, comments)| comments);
+    for comment in comments.unwrap_or_default() {
+        let comment = String::from_utf8_lossy(comment);
+        if let Some((key, value)) = comment.split_once('=') {
+            let key = match key.to_ascii_uppercase().as_str() {
+                "TITLE" => "title",
+                "ARTIST" => "artist",
+                "ALBUM" => "album",
+                "ALBUMARTIST" | "ALBUM ARTIST" => "album_artist",
+                "GENRE" => "genre",
+                "DATE" | "YEAR" => "year",
+                "TRACKNUMBER" => "track",
+                "DISCNUMBER" => "disc",
+                _ => continue,
+            };
+            tags.set(key, value);
+        }
+    }
+}
+
+/// FLAC metadata block type and body.
+pub type Block<'a> = (u8, &'a [u8]);
+
+/// Metadata blocks of a FLAC stream, as many as `data` holds whole, and
+/// where its audio starts, or how many bytes are needed to see the rest.
+pub fn flac_blocks(data: &[u8]) -> (Vec<Block<'_>>, Result<usize, usize>) {
+    let mut blocks = Vec::new();
+    let mut pos = 4;
+    loop {
+        if pos + 4 > data.len() {
+            return (blocks, Err(pos + 4));
+        }
+        let header = data[pos];
+        let end = pos + 4 + (be32(&data[pos..]) & 0x00ff_ffff) as usize;
+        if end > data.len() {
+            return (blocks, Err(end));
+        }
+        blocks.push((header & 0x7f, &data[pos + 4..end]));
+        pos = end;
+        if header & 0x80 != 0 {
+            return (blocks, Ok(pos));
+        }
+    }
+}
+
+fn parse_flac(data: &[u8], tags: &mut Tags) {
+    for (kind, block) in flac_blocks(data).0 {
+        match kind {
+            0 if block.len() >= 18 => {
+                let rate =
+                    (block[10] as u64) << 12 | (block[11] as u64) << 4 | (block[12] as u64) >> 4;
+                let samples = ((block[13] & 0x0f) as u64) << 32 | be32(&block[14..]) as u64;
+                if rate > 0 && samples > 0 {
+                    tags.duration = Some(samples as f64 / rate as f64);
+                }
+            }
+            4 => parse_vorbis_comment(block, tags),
+            _ => (),
+        }
+    }
+}
+
+/// End of the tags at the beginning of a file: ID3v2 and FLAC metadata, or
+/// how many bytes are needed to find it.
+pub fn region_end(head: &[u8]) -> Result<usize, usize> {
+    let id3_end = match id3v2_len(head) {
+        Some(len) if len > head.len() => return Err(len),
+        Some(len) => len,
+        None => 0,
+    };
+    let rest = &head[id3_end..];
+    if !rest.starts_with(b"fLaC") {
+        return Ok(id3_end);
+    }
+    match flac_blocks(rest).1 {
+        Ok(end) => Ok(id3_end + end),
+        Err(needed) => Err(id3_end + needed),
+    }
+}
+
+/// Split a NUL terminated string in `encoding` off the beginning of `data`.
+fn split_id3_string(data: &[u8], encoding: u8) -> Option<(&[u8], &[u8])> {
+    if encoding == 1 || encoding == 2 {
+        let end = data.chunks_exact(2).position(|pair| pair == [0, 0])? * 2;
+        Some((&data[..end], data.get(end + 2..)?))
+    } else {
+        let end = data.iter().position(|&byte| byte == 0)?;
+        Some((&data[..end], data.get(end + 1..)?))
+    }
+}
+
+/// Picture type and image of an APIC or PIC frame.
+fn id3_picture(frame: &Frame) -> Option<(u8, Vec<u8>)> {
+    let (&encoding, data) = frame.body.split_first()?;
+    let data = if frame.id == b"PIC" {
+        // Three letter image format instead of MIME type.
+        data.get(3..)?
+    } else {
+        split_id3_string(data, 0)?.1
+    };
+    let (&kind, data) = data.split_first()?;
+    Some((kind, split_id3_string(data, encoding)?.1.to_vec()))
+}
+
+/// Picture type and image of a FLAC PICTURE block.
+fn flac_picture(block: &[u8]) -> Option<(u8, Vec<u8>)> {
+    let kind = be32(block.get(..4)?) as u8;
+    let mut pos = 4;
+    for _ in 0..2 {
+        // MIME type and description
+        pos += 4 + be32(block.get(pos..pos + 4)?) as usize;
+    }
+    // Width, height, depth and colors
+    pos += 16;
+    let len = be32(block.get(pos..pos + 4)?) as usize;
+    Some((kind, block.get(pos + 4..pos + 4 + len)?.to_vec()))
+}
+
+/// Embedded cover art, the front cover if there are several pictures.
+pub fn picture(head: &[u8]) -> Option<Vec<u8>> {
+    let mut pictures: Vec<(u8, Vec<u8>)> = id3_frames(head)
+        .iter()
+        .filter(|frame| frame.id == b"APIC" || frame.id == b"PIC")
+        .filter_map(id3_picture)
+        .collect();
+    let rest = head.get(id3v2_len(head).unwrap_or(0)..).unwrap_or_default();
+    if rest.starts_with(b"fLaC") {
+        pictures.extend(
+            flac_blocks(rest)
+                .0
+                .into_iter()
+                .filter(|(kind, _)| *kind == 6)
+                .filter_map(|(_, block)| flac_picture(block)),
+        );
+    }
+    pictures.retain(|(_, image)| !image.is_empty());
+    // 3 is the front cover.
+    pictures.sort_by_key(|(kind, _)| *kind != 3);
+    pictures.into_iter().next().map(|(_, image)| image)
+}
+
+/// Packets of the first pages of an Ogg stream.
+fn ogg_packets(data: &[u8], max: usize) -> Vec<Vec<u8>> {
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut pos = 0;
+    while packets.len() < max && pos + 27 <= data.len() && &data[pos..pos + 4] == b"OggS" {
+        let segments = data[pos + 26] as usize;
+        let lacing = match data.get(pos + 27..pos + 27 + segments) {
+            Some(lacing) => lacing,
+            None => break,
+        };
+        let mut body = pos + 27 + segments;
+        for &len in lacing {
+            let len = len as usize;
+            match data.get(body..body + len) {
+                Some(segment) => packet.extend_from_slice(segment),
+                None => return packets,
+            }
+            body += len;
+            if len < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+        pos = body;
+    }
+    packets
+}
+
+fn parse_ogg(data: &[u8], tags: &mut Tags) {
+    let packets = ogg_packets(data, 2);
+    if let Some(comment) = packets.get(1) {
+        if comment.starts_with(b"\x03vorbis") {
+            parse_vorbis_comment(&comment[7..], tags);
+        } else if comment.starts_with(b"OpusTags") {
+            parse_vorbis_comment(&comment[8..], tags);
+        }
+    }
+}
+
+/// Children boxes of an MP4 box body: type and body.
+fn mp4_boxes(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let size = be32(&data[pos..]) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        let end = if size == 0 { data.len() } else { pos + size };
+        if size != 0 && size < 8 || end > data.len() {
+            break;
+        }
+        boxes.push((kind, &data[pos + 8..end]));
+        pos = end;
+    }
+    boxes
+}
+
+fn mp4_child<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    mp4_boxes(data)
+        .into_iter()
+        .find(|(child, _)| *child == kind)
+        .map(|(_, body)| body)
+}
+
+fn parse_mp4(data: &[u8], tags: &mut Tags) {
+    let moov = match mp4_child(data, b"moov") {
+        Some(moov) => moov,
+        None => return,
+    };
+    if let Some(mvhd) = mp4_child(moov, b"mvhd") {
+        let (scale, duration) = match mvhd.first() {
+            Some(1) if mvhd.len() >= 32 => (
+                be32(&mvhd[20..]) as u64,
+                u64::from_be_bytes(mvhd[24..32].try_into().unwrap()),
+            ),
+            Some(0) if mvhd.len() >= 20 => (be32(&mvhd[12..]) as u64, be32(&mvhd[16..]) as u64),
+            _ => (0, 0),
+        };
+        if scale > 0 {
+            tags.duration = Some(duration as f64 / scale as f64);
+        }
+    }
+    let ilst = mp4_child(moov, b"udta")
+        .and_then(|udta| mp4_child(udta, b"meta"))
+        .and_then(|meta| mp4_child(meta.get(4..)?, b"ilst"));
+    for (kind, item) in ilst.map(mp4_boxes).unwrap_or_default() {
+        let data = match mp4_child(item, b"data") {
+            Some(data) if data.len() >= 8 => &data[8..],
+            _ => continue,
+        };
+        let key = match kind {
+            b"\xa9nam" => "title",
+            b"\xa9ART" => "artist",
+            b"\xa9alb" => "album",
+            b"aART" => "album_artist",
+            b"\xa9gen" => "genre",
+            b"\xa9day" => "year",
+            b"trkn" | b"disk" => {
+                if data.len() >= 4 {
+                    let number = u16::from_be_bytes([data[2], data[3]]).to_string();
+                    tags.set(if kind == b"trkn" { "track" } else { "disc" }, &number);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        tags.set(key, &String::from_utf8_lossy(data));
+    }
+}
+
+/// Tags found in the beginning of a file, if any.
+pub fn parse(head: &[u8]) -> Option<Tags> {
+    let mut tags = Tags::default();
+    parse_id3v2(head, &mut tags);
+    // FLAC may come after an ID3v2 tag.
+    let rest = head.get(id3v2_len(head).unwrap_or(0)..).unwrap_or_default();
+    if rest.starts_with(b"fLaC") {
+        parse_flac(rest, &mut tags);
+    } else if rest.starts_with(b"OggS") {
+        parse_ogg(rest, &mut tags);
+    } else if rest.get(4..8) == Some(b"ftyp") {
+        parse_mp4(rest, &mut tags);
+    }
+    Some(tags).filter(|tags| !tags.is_empty())
+}
+
+/// LRC timestamp of `millis`.
+fn lrc_time(millis: u32) -> String {
+    let centis = millis / 10;
+    format!(
+        "[{:02}:{:02}.{:02}]",
+        centis / 6000,
+        centis / 100 % 60,
+        centis % 100
+    )
+}
+
+/// Lyrics of a USLT frame.
+fn uslt(frame: &Frame) -> Option<String> {
+    let (&encoding, data) = frame.body.split_first()?;
+    // Language and description
+    let (_, text) = split_id3_string(data.get(3..)?, encoding)?;
+    decode(encoding, text)
+}
+
+/// Synchronised lyrics of a SYLT frame with timestamps in milliseconds, as LRC.
+fn sylt(frame: &Frame) -> Option<String> {
+    let (&encoding, data) = frame.body.split_first()?;
+    // Language, then timestamp format: 2 for milliseconds, 1 for MPEG frames.
+    if data.get(3) != Some(&2) {
+        return None;
+    }
+    let (_, mut data) = split_id3_string(data.get(5..)?, encoding)?;
+    let mut lrc = String::new();
+    while let Some((text, rest)) = split_id3_string(data, encoding) {
+        let time = match rest.get(..4) {
+            Some(time) => be32(time),
+            None => break,
+        };
+        let text = decode(encoding, text)?;
+        lrc.push_str(&format!("{}{}\n", lrc_time(time), text.trim()));
+        data = &rest[4..];
+    }
+    Some(lrc).filter(|lrc| !lrc.is_empty())
+}
+
+/// Embedded lyrics: synchronised ones turned into LRC, or plain text.
+pub fn lyrics(head: &[u8]) -> Option<String> {
+    let frames = id3_frames(head);
+    let synced = frames
+        .iter()
+        .filter(|frame| frame.id == b"SYLT")
+        .find_map(sylt);
+    let unsynced = || {
+        frames
+            .iter()
+            .filter(|frame| frame.id == b"USLT")
+            .find_map(uslt)
+    };
+    let comment = || {
+        let rest = head.get(id3v2_len(head).unwrap_or(0)..)?;
+        if !rest.starts_with(b"fLaC") {
+            return None;
+        }
+        let blocks = flac_blocks(rest).0;
+        let (_, comments) = blocks
+            .iter()
+            .filter(|(kind, _)| *kind == 4)
+            .find_map(|(_, block)| vorbis_comments(block))?;
+        comments.iter().find_map(|comment| {
+            let comment = String::from_utf8_lossy(comment);
+            let (key, value) = comment.split_once('=')?;
+            match key.to_ascii_uppercase().as_str() {
+                "LYRICS" | "UNSYNCEDLYRICS" => Some(value.to_string()),
+                _ => None,
+            }
+        })
+    };
+    synced
+        .or_else(unsynced)
+        .or_else(comment)
+        .filter(|lyrics| !lyrics.trim().is_empty())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn id3_frame(id: &[u8], text: &str) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        frame.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 3]);
+        frame.extend_from_slice(text.as_bytes());
+        frame
+    }
+
+    pub fn id3(frames: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = frames.concat();
+        let size = body.len();
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        tag.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7f) as u8));
+        tag.extend(body);
+        tag
+    }
+
+    pub fn vorbis_comment(comments: &[&str]) -> Vec<u8> {
+        let mut block = 4u32.to_le_bytes().to_vec();
+        block.extend_from_slice(b"test");
+        block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            block.extend_from_slice(comment.as_bytes());
+        }
+        block
+    }
+
+    #[test]
+    fn id3v2() {
+        let mut head = id3(&[
+            id3_frame(b"TPE1", "Björk"),
+            id3_frame(b"TRCK", "3/12"),
+            id3_frame(b"TLEN", "215000"),
+        ]);
+        head.extend_from_slice(&[0xff, 0xfb, 0x90, 0x00]);
+        let tags = parse(&head).unwrap();
+        assert_eq!(tags.artist.as_deref(), Some("Björk"));
+        assert_eq!(tags.track_number, Some(3));
+        assert_eq!(tags.duration, Some(215.0));
+        // Cut in the middle of a frame.
+        let tags = parse(&head[..30]).unwrap();
+        assert_eq!(tags.track_number, None);
+    }
+
+    #[test]
+    fn flac() {
+        let mut head = b"fLaC".to_vec();
+        let mut streaminfo = vec![0; 34];
+        // 44100 Hz, 441000 samples
+        streaminfo[10..14].copy_from_slice(&[0x0a, 0xc4, 0x40, 0x00]);
+        streaminfo[14..18].copy_from_slice(&441000u32.to_be_bytes());
+        head.extend_from_slice(&[0, 0, 0, 34]);
+        head.extend(streaminfo);
+        let comment = vorbis_comment(&["ALBUM=Homogenic", "tracknumber=7", "DATE=1997-09-22"]);
+        head.extend_from_slice(&(0x8400_0000u32 | comment.len() as u32).to_be_bytes());
+        head.extend(comment);
+        let tags = parse(&head).unwrap();
+        assert_eq!(tags.album.as_deref(), Some("Homogenic"));
+        assert_eq!(tags.track_number, Some(7));
+        assert_eq!(tags.year, Some(1997));
+        assert_eq!(tags.duration, Some(10.0));
+        assert_eq!(parse(b"fLaC\x00\x00"), None);
+        assert_eq!(parse(&[]), None);
+    }
+
+    #[test]
+    fn pictures() {
+        let apic = |kind: u8, image: &[u8]| {
+            let mut body = b"\x00image/jpeg\x00".to_vec();
+            body.push(kind);
+            body.extend_from_slice(b"desc\x00");
+            body.extend_from_slice(image);
+            let mut frame = b"APIC".to_vec();
+            frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&[0, 0]);
+            frame.extend(body);
+            frame
+        };
+        let head = id3(&[apic(4, b"back"), apic(3, b"front")]);
+        assert_eq!(picture(&head), Some(b"front".to_vec()));
+        assert_eq!(picture(&head[..head.len() - 1]), Some(b"back".to_vec()));
+
+        let mut block = 3u32.to_be_bytes().to_vec();
+        block.extend_from_slice(&[0, 0, 0, 3]);
+        block.extend_from_slice(b"png");
+        block.extend_from_slice(&[0; 20]);
+        block.extend_from_slice(&4u32.to_be_bytes());
+        block.extend_from_slice(b"\x89PNG");
+        let mut head = b"fLaC".to_vec();
+        head.extend_from_slice(&(0x8600_0000u32 | block.len() as u32).to_be_bytes());
+        head.extend(block);
+        assert_eq!(picture(&head), Some(b"\x89PNG".to_vec()));
+        assert_eq!(region_end(&head), Ok(head.len()));
+        assert_eq!(region_end(&head[..10]), Err(head.len()));
+    }
+
+    #[test]
+    fn lyrics_frames() {
+        let mut sylt_body = b"\x03eng\x02\x01\x00".to_vec();
+        for (text, time) in [("First", 1500u32), ("Second", 62_340)] {
+            sylt_body.extend_from_slice(text.as_bytes());
+            sylt_body.push(0);
+            sylt_body.extend_from_slice(&time.to_be_bytes());
+        }
+        let frame = |id: &[u8], body: &[u8]| {
+            let mut frame = id.to_vec();
+            frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&[0, 0]);
+            frame.extend_from_slice(body);
+            frame
+        };
+        let uslt = frame(b"USLT", b"\x00engdesc\x00Plain\nlines");
+        let head = id3(std::slice::from_ref(&uslt));
+        assert_eq!(lyrics(&head).as_deref(), Some("Plain\nlines"));
+        let head = id3(&[uslt, frame(b"SYLT", &sylt_body)]);
+        assert_eq!(
+            lyrics(&head).as_deref(),
+            Some("[00:01.50]First\n[01:02.34]Second\n")
+        );
+        let comment = vorbis_comment(&["LYRICS=[00:01.00]Hi"]);
+        let mut head = b"fLaC".to_vec();
+        head.extend_from_slice(&(0x8400_0000u32 | comment.len() as u32).to_be_bytes());
+        head.extend(comment);
+        assert_eq!(lyrics(&head).as_deref(), Some("[00:01.00]Hi"));
+        assert_eq!(lyrics(&[]), None);
+    }
+
+    #[test]
+    fn fill_missing_only() {
+        let tags = Tags {
+            artist: Some("Tag Artist".to_string()),
+            track_number: Some(2),
+            ..Default::default()
+        };
+        let mut track = Track {
+            artist: Some("Library Artist".to_string()),
+            ..Default::default()
+        };
+        tags.fill(&mut track);
+        assert_eq!(track.artist.as_deref(), Some("Library Artist"));
+        assert_eq!(track.track_number, Some(2));
+    }
+}
@@ -0,0 +1,281 @@
+//! Path templates such as `{artist}/{year} - {album}/{name}.{ext}`.
+//!
+//! `/` separates directories. `{a|b|text}` takes the first of fields `a`, `b`
+//! which is set, or `text` if none is. `{field:02}` pads the value with zeros
+//! to two characters, `{field:3}` with spaces. Values never add directories:
+//! path separators and control characters in them are replaced with `_`.
+
+use crate::{get_basename, Track};
+use std::{fmt, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Id,
+    Name,
+    Artist,
+    Album,
+    Genre,
+    Year,
+    Format,
+    Filetype,
+    Ext,
+    Basename,
+    Stem,
+    Track,
+    Disc,
+    AlbumArtist,
+    Bitrate,
+}
+
+const FIELDS: [(&str, Field); 15] = [
+    ("id", Field::Id),
+    ("name", Field::Name),
+    ("artist", Field::Artist),
+    ("album", Field::Album),
+    ("genre", Field::Genre),
+    ("year", Field::Year),
+    ("format", Field::Format),
+    ("filetype", Field::Filetype),
+    ("ext", Field::Ext),
+    ("basename", Field::Basename),
+    ("stem", Field::Stem),
+    ("track", Field::Track),
+    ("disc", Field::Disc),
+    ("album_artist", Field::AlbumArtist),
+    ("bitrate", Field::Bitrate),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Alternative {
+    Field(Field),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Value {
+        alternatives: Vec<Alternative>,
+        width: usize,
+        zero: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    source: String,
+    segments: Vec<Vec<Part>>,
+}
+
+fn field(name: &str) -> Option<Field> {
+    FIELDS
+        .iter()
+        .find(|(field, _)| *field == name)
+        .map(|(_, field)| *field)
+}
+
+fn parse_value(spec: &str) -> Result<Part, String> {
+    let (names, format) = match spec.rfind(':') {
+        Some(colon) => (&spec[..colon], &spec[colon + 1..]),
+        None => (spec, ""),
+    };
+    let width = if format.is_empty() {
+        0
+    } else {
+        format
+            .parse::<usize>()
+            .map_err(|_| format!("Wrong width '{}' in {{{}}}", format, spec))?
+    };
+    let names: Vec<&str> = names.split('|').collect();
+    let mut alternatives = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        match field(name) {
+            Some(field) => alternatives.push(Alternative::Field(field)),
+            // Only a fallback may be plain text, anything else is a typo.
+            None if i > 0 && i == names.len() - 1 => {
+                alternatives.push(Alternative::Text(name.to_string()))
+            }
+            None => return Err(format!("Unknown field '{}' in {{{}}}", name, spec)),
+        }
+    }
+    Ok(Part::Value {
+        alternatives,
+        width,
+        zero: format.starts_with('0'),
+    })
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        for segment in s.split('/').filter(|segment| !segment.is_empty()) {
+            let mut parts = Vec::new();
+            let mut rest = segment;
+            while !rest.is_empty() {
+                match rest.find('{') {
+                    Some(0) => {
+                        let end = rest
+                            .find('}')
+                            .ok_or_else(|| format!("Unclosed '{{' in template '{}'", s))?;
+                        parts.push(parse_value(&rest[1..end])?);
+                        rest = &rest[end + 1..];
+                    }
+                    Some(start) => {
+                        parts.push(Part::Text(rest[..start].to_string()));
+                        rest = &rest[start..];
+                    }
+                    None => {
+                        parts.push(Part::Text(rest.to_string()));
+                        rest = "";
+                    }
+                }
+            }
+            segments.push(parts);
+        }
+        if segments.is_empty() {
+            return Err("Empty path template".to_string());
+        }
+        Ok(Template {
+            source: s.to_string(),
+            segments,
+        })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn value(field: Field, track: &Track) -> Option<String> {
+    let basename = || get_basename(track.path.as_ref());
+    let value = match field {
+        Field::Id => track.id.clone(),
+        Field::Name => track.name.clone(),
+        Field::Artist => track.artist.clone(),
+        Field::Album => track.album.clone(),
+        Field::Genre => track.genre.clone(),
+        Field::Year => track.year.map(|year| year.to_string()),
+        Field::Format => track.format.clone(),
+        Field::Filetype => track.filetype.clone(),
+        Field::Ext => basename()
+            .and_then(|name| Some(name[name.rfind('.')? + 1..].to_string()))
+            .or_else(|| track.filetype.clone())
+            .or_else(|| track.format.clone()),
+        Field::Basename => basename(),
+        Field::Stem => basename().map(|name| match name.rfind('.') {
+            Some(dot) if dot > 0 => name[..dot].to_string(),
+            _ => name,
+        }),
+        Field::Track => track.track_number.map(|number| number.to_string()),
+        Field::Disc => track.disc_number.map(|number| number.to_string()),
+        Field::AlbumArtist => track.album_artist.clone(),
+        Field::Bitrate => track.bitrate.map(|bitrate| bitrate.to_string()),
+    };
+    value.filter(|value| !value.is_empty())
+}
+
+/// Make a field value safe to use inside a single path component.
+pub fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c == '/' || c.is_control() { '_' } else { c })
+        .collect()
+}
+
+impl Template {
+    /// Path of `track`. Empty directories are left out; an empty file name is
+    /// replaced with the server side one.
+    pub fn render(&self, track: &Track) -> Vec<String> {
+        let mut path = Vec::new();
+        for (i, parts) in self.segments.iter().enumerate() {
+            let mut segment = String::new();
+            for part in parts {
+                match part {
+                    Part::Text(text) => segment.push_str(text),
+                    Part::Value {
+                        alternatives,
+                        width,
+                        zero,
+                    } => {
+                        let value = alternatives
+                            .iter()
+                            .find_map(|alternative| match alternative {
+                                Alternative::Field(field) => value(*field, track),
+                                Alternative::Text(text) => Some(text.clone()),
+                            })
+                            .unwrap_or_default();
+                        let value = sanitize(&value);
+                        let pad = width.saturating_sub(value.chars().count());
+                        let fill = if *zero { '0' } else { ' ' };
+                        segment.extend(std::iter::repeat_n(fill, pad));
+                        segment.push_str(&value);
+                    }
+                }
+            }
+            let segment = segment.trim();
+            let last = i == self.segments.len() - 1;
+            if segment.is_empty() || segment == "." || segment == ".." {
+                if last {
+                    path.push(get_basename(track.path.as_ref()).unwrap_or_default());
+                }
+                continue;
+            }
+            path.push(segment.to_string());
+        }
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track() -> Track {
+        Track {
+            id: Some("7".to_string()),
+            name: Some("Intro/Outro".to_string()),
+            artist: Some("Band".to_string()),
+            album: None,
+            genre: None,
+            year: Some(1999),
+            format: Some("flac".to_string()),
+            filetype: None,
+            path: Some("/music/01%20intro.FLAC".to_string()),
+            size: Some(1),
+            track_number: Some(3),
+            ..Default::default()
+        }
+    }
+
+    fn render(template: &str) -> Vec<String> {
+        template.parse::<Template>().unwrap().render(&track())
+    }
+
+    #[test]
+    fn rendering() {
+        assert_eq!(
+            render("{artist}/{year} - {album|Unknown Album}/{id:03} {name}.{ext}"),
+            vec!["Band", "1999 - Unknown Album", "007 Intro_Outro.FLAC"]
+        );
+        assert_eq!(render("{genre}/{album}/{stem}"), vec!["01 intro"]);
+        assert_eq!(
+            render("{album_artist|artist}/{disc|1}-{track:02} {name}"),
+            vec!["Band", "1-03 Intro_Outro"]
+        );
+        assert_eq!(render("{album|genre}/{name:4}"), vec!["Intro_Outro"]);
+        assert_eq!(render("{album}/{genre}"), vec!["01 intro.FLAC"]);
+    }
+
+    #[test]
+    fn errors() {
+        assert!("{artsit}/{name}".parse::<Template>().is_err());
+        assert!("{Unknown|artist}".parse::<Template>().is_err());
+        assert!("{artist".parse::<Template>().is_err());
+        assert!("{name:xx}".parse::<Template>().is_err());
+        assert!("/".parse::<Template>().is_err());
+    }
+}
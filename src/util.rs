@@ -0,0 +1,8 @@
+//! Small helpers shared by modules which have nothing else in common.
+
+/// Stable 64-bit FNV-1a hash, used to name files after track ids.
+pub fn hash(data: &str) -> u64 {
+    data.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}